@@ -0,0 +1,283 @@
+//! Export and import of built sysroots as `.tar.zst` archives
+//!
+//! An archive starts with a `manifest.json` file that records the fingerprint of each sysroot
+//! in it, followed by the `lib/rustlib/<triple>` directories of the sysroots.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::fingerprint::Fingerprint;
+use crate::xargo::Home;
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Deserialize, Serialize)]
+struct Manifest {
+    /// The version of cargo-xbuild that created the archive
+    cargo_xbuild: String,
+    targets: Vec<Archived>,
+}
+
+/// A sysroot in the archive
+#[derive(Deserialize, Serialize)]
+struct Archived {
+    hash: String,
+    fingerprint: Fingerprint,
+}
+
+/// Writes the sysroots of `targets` to the archive at `path`
+pub fn export(path: &Path, home: &Home, targets: Vec<Fingerprint>) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+    let encoder = zstd::Encoder::new(file, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+
+    let manifest = Manifest {
+        cargo_xbuild: env!("CARGO_PKG_VERSION").to_owned(),
+        targets: targets
+            .into_iter()
+            .map(|fingerprint| Archived {
+                hash: fingerprint.hash(),
+                fingerprint,
+            })
+            .collect(),
+    };
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, &json[..])?;
+
+    for archived in &manifest.targets {
+        let triple = archived.fingerprint.triple();
+        let lock = home.lock_ro(triple)?;
+        let dir = lock.parent();
+        for e in WalkDir::new(dir).min_depth(1) {
+            let e = e.with_context(|| {
+                format!(
+                    "intermittent IO error while iterating directory `{}`",
+                    dir.display()
+                )
+            })?;
+            if e.path() == lock.path() {
+                continue;
+            }
+
+            let name = Path::new("lib")
+                .join("rustlib")
+                .join(triple)
+                .join(e.path().strip_prefix(dir).unwrap());
+            builder
+                .append_path_with_name(e.path(), &name)
+                .with_context(|| format!("couldn't add {} to the archive", e.path().display()))?;
+        }
+    }
+
+    builder.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+/// Unpacks the sysroots of `targets` from the archive at `path`
+///
+/// The manifest of the archive is checked first, so that sysroots that were built with
+/// different inputs are rejected. The `git` overrides without a `rev` are locked to the
+/// commits of the archived sysroots.
+pub fn import(path: &Path, home: &Home, mut targets: Vec<Fingerprint>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    let mut entries = archive
+        .entries()
+        .with_context(|| format!("couldn't read {}", path.display()))?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()? != Path::new(MANIFEST) {
+                bail!("{} has no {}", path.display(), MANIFEST);
+            }
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            serde_json::from_str(&manifest)
+                .with_context(|| format!("the {} of {} is invalid", MANIFEST, path.display()))?
+        }
+        None => bail!("{} is empty", path.display()),
+    };
+
+    for fingerprint in &mut targets {
+        let archived = manifest
+            .targets
+            .iter()
+            .find(|archived| archived.fingerprint.triple() == fingerprint.triple())
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} contains no sysroot for `{}`",
+                    path.display(),
+                    fingerprint.triple()
+                )
+            })?;
+        fingerprint.keep_git_commits(&archived.fingerprint);
+        check(archived, fingerprint, &manifest.cargo_xbuild)?;
+    }
+
+    let locks = targets
+        .iter()
+        .map(|fingerprint| home.lock_rw(fingerprint.triple()))
+        .collect::<Result<Vec<_>>>()?;
+    for lock in &locks {
+        lock.remove_siblings()
+            .with_context(|| format!("couldn't clear {}", lock.parent().display()))?;
+    }
+
+    for entry in entries {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let mut components = name.components();
+        let triple = match (components.next(), components.next(), components.next()) {
+            (Some(Component::Normal(lib)), Some(Component::Normal(rustlib)), Some(triple))
+                if lib == "lib" && rustlib == "rustlib" =>
+            {
+                triple.as_os_str().to_owned()
+            }
+            _ => bail!(
+                "{} contains the unexpected file {}",
+                path.display(),
+                name.display()
+            ),
+        };
+        // The hash is written last, so that a partially unpacked sysroot is rebuilt
+        if !targets
+            .iter()
+            .any(|fingerprint| triple == fingerprint.triple())
+            || name.file_name() == Some(".sentinel".as_ref())
+            || name.file_name() == Some(".hash".as_ref())
+        {
+            continue;
+        }
+
+        entry
+            .unpack_in(home.path())
+            .with_context(|| format!("couldn't unpack {}", name.display()))?;
+    }
+
+    for (lock, fingerprint) in locks.iter().zip(&targets) {
+        fingerprint.write(lock.parent())?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the archived sysroot was built with the current fingerprint
+fn check(archived: &Archived, current: &Fingerprint, version: &str) -> Result<()> {
+    if archived.hash == current.hash() {
+        return Ok(());
+    }
+
+    let mut changes = current.changes(&archived.fingerprint);
+    if changes.is_empty() {
+        changes.push(format!(
+            "the archive was created by cargo-xbuild {}, but this is cargo-xbuild {}",
+            version,
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+
+    bail!(
+        "the archived sysroot for `{}` doesn't match the current configuration:\n  {}",
+        current.triple(),
+        changes.join("\n  ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::message::Messages;
+    use crate::observer::StderrObserver;
+    use crate::xargo;
+
+    const TRIPLE: &str = "x86_64-unknown-none";
+
+    fn fingerprint(rustflags: &[&str]) -> Fingerprint {
+        serde_json::from_value(json!({
+            "triple": TRIPLE,
+            "rustc": "rustc 1.60.0-nightly (1bd4fdc94 2022-01-12)",
+            "rustc_commit": "1bd4fdc943513e1004f498bbf289279c9784fc6f",
+            "rustflags": rustflags,
+            "target_spec": null,
+            "profile": "dev",
+            "profile_settings": null,
+            "config": { "crates": ["core"] },
+            "src": null,
+            "lockfile": null,
+        }))
+        .unwrap()
+    }
+
+    fn home(root: &Path) -> Home {
+        let messages = Messages::new(false, Arc::new(StderrObserver::new(true, false)));
+        xargo::home(root, &Config::default(), "dev", &messages).unwrap()
+    }
+
+    /// Exports a sysroot with a `libcore.rlib` that was built with the given rustflags
+    fn exported(dir: &Path, rustflags: &[&str]) -> PathBuf {
+        let home = home(&dir.join("exporter"));
+        let lock = home.lock_rw(TRIPLE).unwrap();
+        fs::create_dir_all(lock.parent().join("lib")).unwrap();
+        fs::write(lock.parent().join("lib").join("libcore.rlib"), "core").unwrap();
+        fingerprint(rustflags).write(lock.parent()).unwrap();
+        drop(lock);
+
+        let archive = dir.join("sysroot.tar.zst");
+        export(&archive, &home, vec![fingerprint(rustflags)]).unwrap();
+        archive
+    }
+
+    #[test]
+    fn import_restores_the_exported_sysroot() {
+        let td = tempfile::tempdir().unwrap();
+        let archive = exported(td.path(), &["-Ccode-model=kernel"]);
+
+        let home = home(&td.path().join("importer"));
+        import(&archive, &home, vec![fingerprint(&["-Ccode-model=kernel"])]).unwrap();
+
+        let dir = home.triple_dir(TRIPLE);
+        assert_eq!(
+            fs::read_to_string(dir.join("lib").join("libcore.rlib")).unwrap(),
+            "core"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join(".hash")).unwrap(),
+            fingerprint(&["-Ccode-model=kernel"]).hash()
+        );
+        assert!(Fingerprint::read(&dir).is_some());
+    }
+
+    #[test]
+    fn import_rejects_a_different_fingerprint() {
+        let td = tempfile::tempdir().unwrap();
+        let archive = exported(td.path(), &["-Ccode-model=kernel"]);
+
+        let home = home(&td.path().join("importer"));
+        let err = import(&archive, &home, vec![fingerprint(&[])]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the archived sysroot for `x86_64-unknown-none` doesn't match the current \
+             configuration:\n  rustflags changed: removed -Ccode-model=kernel"
+        );
+        assert!(!home.triple_dir(TRIPLE).join("lib").exists());
+    }
+}
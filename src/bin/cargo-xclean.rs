@@ -0,0 +1,5 @@
+extern crate xargo_lib;
+
+pub fn main() {
+    xargo_lib::main_common("clean");
+}
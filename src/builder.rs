@@ -0,0 +1,245 @@
+//! A library API to build the sysroot from explicitly given inputs
+//!
+//! Unlike [`build`](crate::build), which parses cargo's command line and runs a cargo command,
+//! [`SysrootBuilder`] only builds the sysroot and leaves running cargo to the caller.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::{env, fmt};
+
+use anyhow::{bail, Result};
+
+use crate::config::Config;
+use crate::observer::{BuildObserver, StderrObserver};
+use crate::{cargo_env, Inputs, Plan};
+
+/// Builds the sysroot for a single target
+///
+/// Inputs that aren't set are resolved like `cargo xbuild` does, e.g. the Rust source from
+/// `XARGO_RUST_SRC` or the `rust-src` component and the rustflags from the environment and
+/// the Cargo configuration.
+///
+/// ```no_run
+/// let builder = xargo_lib::SysrootBuilder::new()
+///     .target("x86_64-unknown-none")
+///     .manifest_path("kernel/Cargo.toml")
+///     .profile("release");
+///
+/// let sysroot = builder.build_sysroot()?;
+/// println!("sysroot in {}", sysroot.path.display());
+///
+/// let status = builder.cargo_command("build")?.status()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct SysrootBuilder {
+    target: Option<String>,
+    manifest_path: Option<PathBuf>,
+    config: Option<Config>,
+    rust_src: Option<PathBuf>,
+    rustflags: Option<Vec<String>>,
+    profile: String,
+    offline: bool,
+    locked: bool,
+    quiet: bool,
+    verbose: bool,
+    observer: Option<Arc<dyn BuildObserver + Send + Sync>>,
+}
+
+/// The result of [`SysrootBuilder::build_sysroot`]
+#[derive(Clone, Debug)]
+pub struct SysrootInfo {
+    /// The sysroot, which can be passed to `rustc` through `--sysroot`
+    pub path: PathBuf,
+    /// The hash of the sysroot's fingerprint, which changes with every input of the build
+    pub hash: String,
+    /// Whether the sysroot was out of date and has been built or restored from the cache
+    pub rebuilt: bool,
+}
+
+impl Default for SysrootBuilder {
+    fn default() -> Self {
+        SysrootBuilder {
+            target: None,
+            manifest_path: None,
+            config: None,
+            rust_src: None,
+            rustflags: None,
+            profile: "dev".to_owned(),
+            offline: false,
+            locked: false,
+            quiet: false,
+            verbose: false,
+            observer: None,
+        }
+    }
+}
+
+impl fmt::Debug for SysrootBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SysrootBuilder")
+            .field("target", &self.target)
+            .field("manifest_path", &self.manifest_path)
+            .field("config", &self.config)
+            .field("rust_src", &self.rust_src)
+            .field("rustflags", &self.rustflags)
+            .field("profile", &self.profile)
+            .field("offline", &self.offline)
+            .field("locked", &self.locked)
+            .field("quiet", &self.quiet)
+            .field("verbose", &self.verbose)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SysrootBuilder {
+    pub fn new() -> Self {
+        SysrootBuilder::default()
+    }
+
+    /// The target triple or the path of a target specification file. Defaults to the
+    /// `build.target` configuration key, which must select a single target, or to the host.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// The manifest of the package to build. Defaults to the nearest `Cargo.toml`.
+    pub fn manifest_path(mut self, manifest_path: impl Into<PathBuf>) -> Self {
+        self.manifest_path = Some(manifest_path.into());
+        self
+    }
+
+    /// The cargo-xbuild config, instead of the one in the manifest
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// The `library` directory of the Rust source
+    pub fn rust_src(mut self, rust_src: impl Into<PathBuf>) -> Self {
+        self.rust_src = Some(rust_src.into());
+        self
+    }
+
+    /// The rustflags, instead of the ones in the environment and the Cargo configuration
+    pub fn rustflags<I, S>(mut self, rustflags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.rustflags = Some(rustflags.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// The name of the profile, like `dev` (the default) or `release`
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// Whether cargo must not access the network
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Whether the lockfile of the Rust source must not change, like with `--locked`
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.locked = locked;
+        self
+    }
+
+    /// Whether no progress is printed to stderr
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Whether the commands that are run are printed to stderr
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Reports the progress to `observer` instead of printing it to stderr like a
+    /// [`StderrObserver`] that is configured with [`quiet`](SysrootBuilder::quiet) and
+    /// [`verbose`](SysrootBuilder::verbose)
+    pub fn observer(mut self, observer: Arc<dyn BuildObserver + Send + Sync>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Builds the sysroot unless it is up to date
+    pub fn build_sysroot(&self) -> Result<SysrootInfo> {
+        let plan = self.plan()?;
+
+        Ok(plan.update_sysroots()?.remove(0))
+    }
+
+    /// Returns a cargo command that runs `subcommand` with the sysroot, e.g. `build`
+    ///
+    /// The command selects the manifest and the profile and sets the target and the rustflags
+    /// through environment variables. More arguments can be added before running it. The
+    /// sysroot isn't built, see [`build_sysroot`](SysrootBuilder::build_sysroot).
+    pub fn cargo_command(&self, subcommand: &str) -> Result<Command> {
+        let plan = self.plan()?;
+        let (cmode, rustflags) = &plan.sysroot_targets()?[0];
+
+        let mut cmd = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()));
+        cmd.arg(subcommand);
+        if let Some(ref manifest_path) = self.manifest_path {
+            cmd.arg("--manifest-path").arg(manifest_path);
+        }
+        cmd.args(["--profile", plan.profile.name()]);
+
+        // Cargo prefers the encoded variables, so the plain ones would only be confusing
+        cmd.env_remove("RUSTFLAGS");
+        cmd.env_remove("RUSTDOCFLAGS");
+        cmd.envs(cargo_env(cmode, rustflags, &plan.home));
+
+        Ok(cmd)
+    }
+
+    fn plan(&self) -> Result<Plan> {
+        let observer = self
+            .observer
+            .clone()
+            .unwrap_or_else(|| Arc::new(StderrObserver::new(self.quiet, self.verbose)));
+
+        let plan = Plan::new(Inputs {
+            manifest_path: self.manifest_path.as_deref(),
+            packages: &[],
+            all: false,
+            exclude: &[],
+            crate_config: self.config.clone(),
+            cargo_config: &[],
+            targets: self.target.as_slice(),
+            rust_src: self.rust_src.as_deref(),
+            rustflags: self.rustflags.as_deref(),
+            profile: &self.profile,
+            offline: self.offline,
+            locked: self.locked,
+            json: false,
+            verbose: self.verbose,
+            observer,
+        })?;
+
+        // The builder builds a single sysroot
+        let targets = plan.sysroot_targets()?;
+        if targets.len() > 1 {
+            bail!(
+                "the `build.target` configuration key selects the targets {}; select one of \
+                 them with `SysrootBuilder::target`",
+                targets
+                    .iter()
+                    .map(|(cmode, _)| format!("`{}`", cmode.orig_triple()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(plan)
+    }
+}
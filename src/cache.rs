@@ -0,0 +1,251 @@
+//! A machine-wide cache of built sysroots that is shared between workspaces
+//!
+//! Each entry lives in `<cache>/<hash>/`, where the hash is the one of the sysroot's
+//! fingerprint. An entry contains the `lib` directory of the sysroot and a `.stamp`
+//! file, which marks the entry as complete and whose modification time records when the entry
+//! was last used. Entries are locked through their `.sentinel` file while they are read or
+//! written, and the cache directory is locked through `.lock` while entries are evicted.
+
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use walkdir::WalkDir;
+
+use crate::flock::Filesystem;
+use crate::message::Messages;
+use crate::util;
+
+/// The default size limit of the cache
+const DEFAULT_MAX_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+pub struct Cache {
+    path: Filesystem,
+    max_size: u64,
+    messages: Messages,
+}
+
+impl Cache {
+    /// Returns the cache if it is enabled through the `XBUILD_CACHE` environment variable
+    ///
+    /// The cache is placed in `XBUILD_CACHE_DIR` if set, and in `$XDG_CACHE_HOME/cargo-xbuild`
+    /// otherwise. Its size is limited to `XBUILD_CACHE_SIZE`.
+    pub fn from_env(messages: &Messages) -> Result<Option<Cache>> {
+        match env::var("XBUILD_CACHE") {
+            Ok(ref value) if !value.is_empty() && value != "0" && value != "false" => {}
+            _ => return Ok(None),
+        }
+
+        let path = match env::var_os("XBUILD_CACHE_DIR") {
+            Some(path) => PathBuf::from(path),
+            None => env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "couldn't determine the cache directory; \
+                         set XBUILD_CACHE_DIR to enable the sysroot cache"
+                    )
+                })?
+                .join("cargo-xbuild"),
+        };
+
+        let max_size = match env::var("XBUILD_CACHE_SIZE") {
+            Ok(size) => parse_size(&size)
+                .with_context(|| format!("invalid XBUILD_CACHE_SIZE `{}`", size))?,
+            Err(_) => DEFAULT_MAX_SIZE,
+        };
+
+        Ok(Some(Cache {
+            path: Filesystem::new(path, messages),
+            max_size,
+            messages: messages.clone(),
+        }))
+    }
+
+    /// Copies the cached sysroot for `triple` with the given hash into `dst`
+    ///
+    /// Files are hardlinked if possible. Returns `false` if the cache has no such sysroot.
+    pub fn fetch(&self, triple: &str, hash: &str, dst: &Path) -> Result<bool> {
+        let entry = self.path.join(hash);
+        if !entry.path().join(".sentinel").exists() {
+            return Ok(false);
+        }
+
+        let lock = entry
+            .open_ro(".sentinel", "the sysroot cache")
+            .with_context(|| format!("couldn't lock {} as read-only", entry.display()))?;
+        let stamp = lock.parent().join(".stamp");
+        if !stamp.exists() {
+            return Ok(false);
+        }
+
+        link_r(&lock.parent().join("lib"), &dst.join("lib"))?;
+        util::write(&stamp, "")?;
+
+        self.messages.observer().info(
+            "Restored",
+            &format!("{} sysroot from {}", triple, entry.display()),
+        );
+
+        Ok(true)
+    }
+
+    /// Stores the sysroot with the given hash, which has been built into `src`, and evicts the
+    /// least recently used entries if the cache grew too large
+    pub fn store(&self, hash: &str, src: &Path) -> Result<()> {
+        let entry = self.path.join(hash);
+        let lock = entry
+            .open_rw(".sentinel", "the sysroot cache")
+            .with_context(|| format!("couldn't lock {} as read-write", entry.display()))?;
+        let stamp = lock.parent().join(".stamp");
+        if !stamp.exists() {
+            lock.remove_siblings()
+                .with_context(|| format!("couldn't clear {}", entry.display()))?;
+            link_r(&src.join("lib"), &lock.parent().join("lib"))?;
+        }
+        util::write(&stamp, "")?;
+
+        // Only one entry is locked at a time to avoid deadlocks with other processes
+        mem::drop(lock);
+
+        self.evict(hash)
+    }
+
+    /// Removes the least recently used entries until the cache is smaller than its size limit
+    ///
+    /// The entry with the hash `keep`, which has just been stored, is never removed, even if
+    /// it is larger than the limit on its own.
+    fn evict(&self, keep: &str) -> Result<()> {
+        let _lock = self
+            .path
+            .open_rw(".lock", "the sysroot cache")
+            .with_context(|| format!("couldn't lock {} as read-write", self.path.display()))?;
+
+        let mut entries = vec![];
+        let mut total = 0;
+        for entry in fs::read_dir(self.path.path())
+            .with_context(|| format!("couldn't read {}", self.path.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("couldn't read {}", self.path.display()))?
+                .path();
+            let used = match path.join(".stamp").metadata() {
+                Ok(metadata) => metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                Err(_) => continue,
+            };
+            // Files that are hardlinked into sysroots still take up their space
+            let size = util::disk_usage(&path)?;
+            total += size;
+            if path.file_name() != Some(keep.as_ref()) {
+                entries.push((used, size, path));
+            }
+        }
+
+        entries.sort();
+        for (_, size, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+
+            let entry = Filesystem::new(path, &self.messages);
+            let lock = entry
+                .open_rw(".sentinel", "the sysroot cache")
+                .with_context(|| format!("couldn't lock {} as read-write", entry.display()))?;
+            lock.remove_siblings()
+                .with_context(|| format!("couldn't clear {}", entry.display()))?;
+            mem::drop(lock);
+
+            // The emptied entry is a cache miss for processes that locked it in the meantime.
+            // If one of them stores the sysroot again, the directory isn't empty and stays.
+            fs::remove_file(entry.path().join(".sentinel")).ok();
+            fs::remove_dir(entry.path()).ok();
+            total -= size;
+        }
+
+        Ok(())
+    }
+}
+
+/// Recreates the directory tree `src` in `dst`, hardlinking the files if possible and copying
+/// them otherwise
+///
+/// Sysroot files are never modified in place, so the links can't change behind our back.
+fn link_r(src: &Path, dst: &Path) -> Result<()> {
+    for e in WalkDir::new(src) {
+        let e = e.with_context(|| {
+            format!(
+                "intermittent IO error while iterating directory `{}`",
+                src.display()
+            )
+        })?;
+
+        let dst_file = dst.join(e.path().strip_prefix(src).unwrap());
+        if e.file_type().is_dir() {
+            fs::create_dir_all(&dst_file)
+                .with_context(|| format!("Could not create directory `{}`", dst_file.display()))?;
+        } else {
+            fs::hard_link(e.path(), &dst_file)
+                .or_else(|_| fs::copy(e.path(), &dst_file).map(|_| ()))
+                .with_context(|| {
+                    format!(
+                        "copying files from `{}` to `{}` failed",
+                        e.path().display(),
+                        dst_file.display()
+                    )
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a size in bytes with an optional `K`, `M`, `G` or `T` suffix (powers of 1024)
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let shift = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => bail!("unknown unit `{}`", unit),
+    };
+    let number: u64 = number.parse()?;
+
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("the size is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1536").unwrap(), 1536);
+        assert_eq!(parse_size("2K").unwrap(), 2 << 10);
+        assert_eq!(parse_size("2kB").unwrap(), 2 << 10);
+        assert_eq!(parse_size("10M").unwrap(), 10 << 20);
+        assert_eq!(parse_size("10 MiB").unwrap(), 10 << 20);
+        assert_eq!(parse_size(" 2G ").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn parse_size_errors() {
+        for size in &["", "G", "1.5G", "-1", "10X", "10 GB extra", "99999999999T"] {
+            assert!(parse_size(size).is_err(), "{:?} was accepted", size);
+        }
+    }
+}
@@ -1,64 +1,88 @@
-use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
 use std::{env, fmt};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use cargo_platform::{Cfg, CfgExpr};
+use toml::value::Table;
 use toml::Value;
 
 use crate::cli::Args;
 use crate::extensions::CommandExt;
-use crate::util;
+use crate::message::Messages;
 use crate::xargo::Home;
+use crate::{rustc, util};
+use crate::{CompilationMode, Error};
 
+#[derive(PartialEq)]
 pub struct Rustflags {
     flags: Vec<String>,
 }
 
 impl Rustflags {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
+    pub fn new(flags: Vec<String>) -> Rustflags {
+        Rustflags { flags }
+    }
+
+    /// Returns these flags without linker arguments, which don't affect the compilation of
+    /// `.rlib`s
+    fn without_link_args(&self) -> Vec<&str> {
+        fn is_link_arg(codegen_option: &str) -> bool {
+            codegen_option.starts_with("link-arg=") || codegen_option.starts_with("link-args=")
+        }
+
+        let mut result = vec![];
         let mut flags = self.flags.iter();
 
         while let Some(flag) = flags.next() {
             if flag == "-C" {
-                if let Some(next) = flags.next() {
-                    if next.starts_with("link-arg=") || next.starts_with("link-args=") {
-                        // don't hash linker arguments
-                    } else {
-                        flag.hash(hasher);
-                        next.hash(hasher);
-                    }
-                } else {
-                    flag.hash(hasher);
+                match flags.next() {
+                    // don't include linker arguments
+                    Some(next) if is_link_arg(next) => {}
+                    Some(next) => result.extend([&**flag, &**next]),
+                    None => result.push(&**flag),
                 }
-            } else {
-                flag.hash(hasher);
+            } else if !flag.strip_prefix("-C").is_some_and(is_link_arg) {
+                result.push(&**flag);
             }
         }
+
+        result
     }
 
-    /// Stringifies these flags for Xargo consumption
-    pub fn for_xargo(&self, home: &Home) -> Result<String> {
-        let sysroot = format!("{}", home.display());
-        if env::var_os("XBUILD_ALLOW_SYSROOT_SPACES").is_none() && sysroot.contains(" ") {
-            return Err(anyhow!(
-                "Sysroot must not contain spaces!\n\
-                See issue https://github.com/rust-lang/cargo/issues/6139\n\n\
-                The sysroot is `{}`.\n\n\
-                To override this error, you can set the `XBUILD_ALLOW_SYSROOT_SPACES`\
-                environment variable.",
-                sysroot
-            ));
+    /// Returns the flags that the sysroot crates should be compiled with
+    ///
+    /// These are the flags without linker arguments and without any `--sysroot`, since the
+    /// sysroot crates are built against the sysroot of `rustc`.
+    pub fn for_sysroot(&self) -> Vec<String> {
+        let mut result = vec![];
+        let mut flags = self.without_link_args().into_iter();
+
+        while let Some(flag) = flags.next() {
+            if flag == "--sysroot" {
+                flags.next();
+            } else if !flag.starts_with("--sysroot=") {
+                result.push(flag.to_owned());
+            }
         }
+
+        result
+    }
+
+    /// Encodes these flags for Xargo consumption through `CARGO_ENCODED_RUSTFLAGS`
+    ///
+    /// The flags are separated by `0x1f` so that they may contain spaces, e.g. in the
+    /// sysroot path.
+    pub fn for_xargo(&self, home: &Home) -> String {
         let mut flags = self.flags.clone();
-        if !flags.contains(&String::from("--sysroot")) {
+        if !flags
+            .iter()
+            .any(|f| f == "--sysroot" || f.starts_with("--sysroot="))
+        {
             flags.push("--sysroot".to_owned());
-            flags.push(sysroot);
+            flags.push(home.display().to_string());
         }
-        Ok(flags.join(" "))
+        flags.join("\x1f")
     }
 }
 
@@ -68,14 +92,32 @@ impl fmt::Display for Rustflags {
     }
 }
 
-pub fn rustflags(config: Option<&Config>, target: &str) -> Result<Rustflags> {
-    flags(config, target, "rustflags").map(|fs| Rustflags { flags: fs })
+pub fn rustflags(
+    config: Option<&Config>,
+    cmode: &CompilationMode,
+    verbose: bool,
+) -> Result<Rustflags> {
+    flags(config, cmode, "rustflags", verbose).map(|fs| Rustflags { flags: fs })
 }
 
 /// Returns the flags for `tool` (e.g. rustflags)
 ///
-/// This looks into the environment and into `.cargo/config`
-fn flags(config: Option<&Config>, target: &str, tool: &str) -> Result<Vec<String>> {
+/// This looks into the environment (`CARGO_ENCODED_<TOOL>` first, then `<TOOL>`) and into
+/// the Cargo configuration
+fn flags(
+    config: Option<&Config>,
+    cmode: &CompilationMode,
+    tool: &str,
+    verbose: bool,
+) -> Result<Vec<String>> {
+    if let Some(t) = env::var_os(format!("CARGO_ENCODED_{}", tool.to_uppercase())) {
+        let t = t.to_string_lossy();
+        if t.is_empty() {
+            return Ok(vec![]);
+        }
+        return Ok(t.split('\x1f').map(|w| w.to_owned()).collect());
+    }
+
     if let Some(t) = env::var_os(tool.to_uppercase()) {
         return Ok(t
             .to_string_lossy()
@@ -84,150 +126,392 @@ fn flags(config: Option<&Config>, target: &str, tool: &str) -> Result<Vec<String
             .collect());
     }
 
-    if let Some(config) = config.as_ref() {
-        let mut build = false;
-        if let Some(array) = config
-            .table
-            .get("target")
-            .and_then(|v| v.get(target))
-            .and_then(|v| v.get(tool))
-            .or_else(|| {
-                build = true;
-                config.table.get("build").and_then(|v| v.get(tool))
-            })
-        {
-            let mut flags = vec![];
-
-            let mut error = false;
-            if let Value::Array(array) = array {
-                for value in array {
-                    if let Some(flag) = value.as_str() {
-                        flags.push(flag.to_owned());
-                    } else {
-                        error = true;
-                        break;
-                    }
-                }
-            } else {
-                error = true;
-            }
+    let target = cmode.triple();
 
-            if error {
-                if build {
-                    Err(anyhow!(
-                        ".cargo/config: build.{} must be an array \
-                         of strings",
-                        tool
-                    ))?
-                } else {
-                    Err(anyhow!(
-                        ".cargo/config: target.{}.{} must be an \
-                         array of strings",
-                        target,
-                        tool
-                    ))?
-                }
-            } else {
-                Ok(flags)
-            }
-        } else {
-            Ok(vec![])
-        }
+    // `target.<triple>.<tool>`, all matching `target.'cfg(..)'.<tool>` and
+    // `CARGO_TARGET_<TRIPLE>_<TOOL>` are joined together
+    let mut flags = match config
+        .and_then(|c| c.table.get("target"))
+        .and_then(Value::as_table)
+    {
+        Some(table) => target_flags(table, target, tool, || {
+            rustc::cfg(cmode.target_spec(), verbose)
+        })?,
+        None => None,
+    };
+
+    // The environment variable also applies without any Cargo configuration
+    let env_key = format!(
+        "CARGO_TARGET_{}_{}",
+        target.to_uppercase().replace(['-', '.'], "_"),
+        tool.to_uppercase()
+    );
+    if let Ok(env_flags) = env::var(&env_key) {
+        flags
+            .get_or_insert_with(Vec::new)
+            .extend(env_flags.split_whitespace().map(String::from));
+    }
+
+    if let Some(flags) = flags {
+        Ok(flags)
+    } else if let Some(value) = config
+        .and_then(|c| c.table.get("build"))
+        .and_then(|v| v.get(tool))
+    {
+        string_list(value, &format!("build.{}", tool))
     } else {
         Ok(vec![])
     }
 }
 
-pub fn run(args: &Args, verbose: bool) -> Result<ExitStatus> {
+/// Returns the `target.<triple>.<tool>` flags of the `target` table followed by the ones of all
+/// `target.'cfg(..)'.<tool>` keys that match the cfg of `target`, or `None` if there are no
+/// such keys
+///
+/// `cfg` is only called if the table has `cfg(..)` keys.
+fn target_flags(
+    table: &Table,
+    target: &str,
+    tool: &str,
+    cfg: impl FnOnce() -> Result<Vec<Cfg>>,
+) -> Result<Option<Vec<String>>> {
+    let mut flags: Option<Vec<String>> = None;
+    if let Some(value) = table.get(target).and_then(|v| v.get(tool)) {
+        flags = Some(string_list(value, &format!("target.{}.{}", target, tool))?);
+    }
+
+    let mut cfg = Some(cfg);
+    let mut target_cfg = None;
+    for (key, value) in table {
+        let value = match value.get(tool) {
+            Some(value) if key.starts_with("cfg(") => value,
+            _ => continue,
+        };
+        if let Some(cfg) = cfg.take() {
+            target_cfg = Some(cfg()?);
+        }
+        if CfgExpr::matches_key(key, target_cfg.as_ref().unwrap()) {
+            flags
+                .get_or_insert_with(Vec::new)
+                .extend(string_list(value, &format!("target.'{}'.{}", key, tool))?);
+        }
+    }
+
+    Ok(flags)
+}
+
+/// Interprets the config value of `key` as a list of strings
+///
+/// Like Cargo, this accepts both an array of strings and a space-separated string.
+fn string_list(value: &Value, key: &str) -> Result<Vec<String>> {
+    if let Some(s) = value.as_str() {
+        return Ok(s.split_whitespace().map(String::from).collect());
+    }
+
+    value
+        .as_array()
+        .and_then(|array| {
+            array
+                .iter()
+                .map(|v| v.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| {
+            Error::ConfigParse {
+                message: format!(
+                    "cargo config: {} must be a string or an array of strings",
+                    key
+                ),
+                source: None,
+            }
+            .into()
+        })
+}
+
+pub fn run(args: &Args, messages: &Messages) -> Result<ExitStatus> {
     let cargo = std::env::var("CARGO").unwrap_or("cargo".to_string());
     Command::new(cargo)
         .arg("build")
         .args(args.all())
-        .run_and_get_status(verbose)
+        .run_and_get_status(messages)
 }
 
+/// The Cargo configuration, merged from all the places Cargo reads it from
 #[derive(Debug)]
 pub struct Config {
-    parent_path: PathBuf,
     table: Value,
+    /// The directory that a relative `build.target` path is resolved against
+    target_base: PathBuf,
 }
 
 impl Config {
-    pub fn target(&self) -> Result<Option<String>> {
-        if let Some(v) = self.table.get("build").and_then(|v| v.get("target")) {
-            let target = v
-                .as_str()
-                .ok_or_else(|| anyhow!(".cargo/config: build.target must be a string"))?;
-            if target.ends_with(".json") {
-                let target_path = self.parent_path.join(target);
-                let canonicalized = target_path.canonicalize().map_err(|err| {
-                    anyhow!(
-                        "target JSON file {} does not exist: {}",
-                        target_path.display(),
-                        err
-                    )
-                })?;
-                let as_string = canonicalized
-                    .into_os_string()
-                    .into_string()
-                    .map_err(|err| anyhow!("target path not valid utf8: {:?}", err))?;
-                Ok(Some(as_string))
-            } else {
-                Ok(Some(target.to_owned()))
+    /// The `build.target` key, which is either a single target or an array of targets
+    pub fn targets(&self) -> Result<Vec<String>> {
+        const TARGET_TYPE: &str =
+            "cargo config: build.target must be a string or an array of strings";
+
+        let value = match self.table.get("build").and_then(|v| v.get("target")) {
+            Some(value) => value,
+            None => return Ok(vec![]),
+        };
+        let targets = match value {
+            Value::String(target) => vec![&**target],
+            Value::Array(array) => array
+                .iter()
+                .map(Value::as_str)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| Error::ConfigParse {
+                    message: TARGET_TYPE.to_owned(),
+                    source: None,
+                })?,
+            _ => bail!(Error::ConfigParse {
+                message: TARGET_TYPE.to_owned(),
+                source: None,
+            }),
+        };
+
+        targets
+            .into_iter()
+            .map(|target| {
+                if target.ends_with(".json") {
+                    let target_path = self.target_base.join(target);
+                    let canonicalized = target_path
+                        .canonicalize()
+                        .with_context(|| {
+                            format!("target JSON file {} does not exist", target_path.display())
+                        })
+                        .context(Error::TargetNotFound {
+                            target: target_path.display().to_string(),
+                        })?;
+                    canonicalized
+                        .into_os_string()
+                        .into_string()
+                        .map_err(|err| anyhow!("target path not valid utf8: {:?}", err))
+                } else {
+                    Ok(target.to_owned())
+                }
+            })
+            .collect()
+    }
+
+    /// The `net.offline` key
+    pub fn offline(&self) -> bool {
+        self.table
+            .get("net")
+            .and_then(|v| v.get("offline"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Merges `layer` into this configuration, `layer` taking precedence
+    ///
+    /// `base` is the directory that relative paths in `layer` are resolved against.
+    fn merge(&mut self, layer: Value, base: &Path) {
+        if layer.get("build").and_then(|v| v.get("target")).is_some() {
+            self.target_base = base.to_owned();
+        }
+        merge(&mut self.table, layer);
+    }
+}
+
+/// Merges `other` into `value` like Cargo merges configuration files: tables are merged
+/// recursively, arrays are concatenated and other values are replaced
+fn merge(value: &mut Value, other: Value) {
+    match (value, other) {
+        (Value::Table(table), Value::Table(other)) => {
+            for (key, other) in other {
+                match table.get_mut(&key) {
+                    Some(value) => merge(value, other),
+                    None => {
+                        table.insert(key, other);
+                    }
+                }
             }
-        } else {
-            Ok(None)
         }
+        (Value::Array(array), Value::Array(other)) => array.extend(other),
+        (value, other) => *value = other,
     }
 }
 
-pub fn config() -> Result<Option<Config>> {
+/// Returns the config file in the `.cargo`-like directory `dir`, if any
+///
+/// Like Cargo, this prefers the legacy `config` file over `config.toml` when both exist.
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    ["config", "config.toml"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+        env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".cargo"))
+    })
+}
+
+/// Loads the Cargo configuration like Cargo itself does
+///
+/// These are the sources, from lowest to highest precedence:
+///
+/// - `$CARGO_HOME/config.toml`
+/// - `.cargo/config.toml` in the current directory and its parents, deeper directories
+///   taking precedence
+/// - the `CARGO_BUILD_TARGET`, `CARGO_BUILD_RUSTFLAGS` and `CARGO_NET_OFFLINE` environment
+///   variables
+/// - the `--config` command line arguments (`cli_config`), later ones taking precedence
+pub fn config(cli_config: &[String]) -> Result<Option<Config>> {
     let cd = env::current_dir().with_context(|| "couldn't get the current directory")?;
 
-    if let Some(p) = util::search(&cd, ".cargo/config") {
-        Ok(Some(Config {
-            parent_path: p.to_owned(),
-            table: util::parse(&p.join(".cargo/config"))?,
-        }))
-    } else {
-        Ok(None)
+    let mut files = cd
+        .ancestors()
+        .filter_map(|dir| config_file(&dir.join(".cargo")))
+        .collect::<Vec<_>>();
+    if let Some(file) = cargo_home().and_then(|home| config_file(&home)) {
+        if !files.iter().any(|f| same_file(f, &file)) {
+            files.push(file);
+        }
+    }
+
+    let mut config = Config {
+        table: Value::Table(Default::default()),
+        target_base: cd.clone(),
+    };
+    let mut found = false;
+
+    for file in files.iter().rev() {
+        // relative paths are relative to the directory containing the `.cargo` directory
+        let base = file.parent().and_then(Path::parent).unwrap_or(&cd);
+        config.merge(util::parse(file)?, base);
+        found = true;
+    }
+
+    let mut env_layer = toml::map::Map::new();
+    if let Ok(target) = env::var("CARGO_BUILD_TARGET") {
+        env_layer.insert("target".to_owned(), Value::String(target));
+    }
+    if let Ok(flags) = env::var("CARGO_BUILD_RUSTFLAGS") {
+        let flags = flags
+            .split_whitespace()
+            .map(|f| Value::String(f.to_owned()))
+            .collect();
+        env_layer.insert("rustflags".to_owned(), Value::Array(flags));
+    }
+    let mut layer = toml::map::Map::new();
+    if !env_layer.is_empty() {
+        layer.insert("build".to_owned(), Value::Table(env_layer));
+    }
+    if let Ok(offline) = env::var("CARGO_NET_OFFLINE") {
+        // Like Cargo, only accept `true` and `false` for booleans
+        let offline = match &*offline {
+            "true" => true,
+            "false" => false,
+            _ => bail!(Error::ConfigParse {
+                message: format!(
+                    "invalid value `{}` for CARGO_NET_OFFLINE: expected a boolean \
+                     (`true` or `false`)",
+                    offline
+                ),
+                source: None,
+            }),
+        };
+        let mut net = toml::map::Map::new();
+        net.insert("offline".to_owned(), Value::Boolean(offline));
+        layer.insert("net".to_owned(), Value::Table(net));
     }
+    if !layer.is_empty() {
+        config.merge(Value::Table(layer), &cd);
+        found = true;
+    }
+
+    for arg in cli_config {
+        let path = Path::new(arg);
+        if path.extension() == Some("toml".as_ref()) && path.is_file() {
+            let path = cd.join(path);
+            let base = match path.parent() {
+                Some(dir) if dir.ends_with(".cargo") => dir.parent().unwrap_or(&cd),
+                Some(dir) => dir,
+                None => &cd,
+            };
+            config.merge(util::parse(&path)?, base);
+        } else {
+            let layer = arg.parse::<Value>().map_err(|e| Error::ConfigParse {
+                message: format!(
+                    "--config argument `{}` is neither a TOML file nor a valid \
+                     `KEY=VALUE` pair",
+                    arg
+                ),
+                source: Some(e.into()),
+            })?;
+            config.merge(layer, &cd);
+        }
+        found = true;
+    }
+
+    Ok(if found { Some(config) } else { None })
 }
 
-pub struct Profile<'t> {
-    table: &'t Value,
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
 }
 
-impl<'t> Profile<'t> {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
-        let mut v = self.table.clone();
+/// A profile together with the profiles it inherits from
+pub struct Profile {
+    name: String,
+    /// The `[profile.*]` sections that make up the profile, by name
+    sections: toml::map::Map<String, Value>,
+}
 
-        // Don't include `lto` in the hash because it doesn't affect compilation
-        // of `.rlib`s
-        if let Value::Table(ref mut table) = v {
-            table.remove("lto");
+impl Profile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-            // don't hash an empty map
-            if table.is_empty() {
-                return;
+    /// The directory below `target/<triple>` that Cargo places the artifacts of the profile in
+    pub fn dir_name(&self) -> &str {
+        match &*self.name {
+            "dev" | "test" => "debug",
+            "release" | "bench" => "release",
+            name => name,
+        }
+    }
+
+    /// Returns the profile sections as they go into the sysroot fingerprint, or `None` if they
+    /// are all empty
+    pub fn fingerprint(&self) -> Option<String> {
+        let mut sections = toml::map::Map::new();
+        for (name, section) in &self.sections {
+            let mut section = section.clone();
+
+            // Don't include `lto` in the fingerprint because it doesn't affect compilation
+            // of `.rlib`s
+            if let Value::Table(ref mut table) = section {
+                table.remove("lto");
+
+                if table.is_empty() {
+                    continue;
+                }
             }
+
+            sections.insert(name.clone(), section);
         }
 
-        v.to_string().hash(hasher);
+        if sections.is_empty() {
+            None
+        } else {
+            Some(Value::Table(sections).to_string())
+        }
     }
 }
 
-impl<'t> fmt::Display for Profile<'t> {
+impl fmt::Display for Profile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut map = toml::map::Map::new();
-        map.insert("profile".to_owned(), {
-            let mut map = toml::map::Map::new();
-            map.insert("release".to_owned(), self.table.clone());
-            Value::Table(map)
-        });
+        map.insert("profile".to_owned(), Value::Table(self.sections.clone()));
 
         fmt::Display::fmt(&Value::Table(map), f)
     }
@@ -251,15 +535,7 @@ pub struct Toml {
 }
 
 impl Toml {
-    /// `profile.release` part of `Cargo.toml`
-    pub fn profile(&self) -> Option<Profile> {
-        self.table
-            .get("profile")
-            .and_then(|v| v.get("release"))
-            .map(|t| Profile { table: t })
-    }
-
-    pub fn features(&self) -> Option<Features> {
+    pub fn features(&self) -> Option<Features<'_>> {
         self.table
             .get("cargo-features")
             .map(|a| Features { array: a })
@@ -269,3 +545,331 @@ impl Toml {
 pub fn toml(root: &Path) -> Result<Toml> {
     util::parse(&root.join("Cargo.toml")).map(|t| Toml { table: t })
 }
+
+/// Resolves the profile `name` from the `[profile]` sections of `Cargo.toml` and of the Cargo
+/// configuration, following `inherits` like Cargo does
+pub fn profile(toml: &Toml, config: Option<&Config>, name: &str) -> Result<Profile> {
+    let mut profiles = toml
+        .table
+        .get("profile")
+        .cloned()
+        .unwrap_or_else(|| Value::Table(Default::default()));
+    if let Some(layer) = config.and_then(|c| c.table.get("profile")) {
+        merge(&mut profiles, layer.clone());
+    }
+
+    let mut sections = toml::map::Map::new();
+    let mut next = Some(name.to_owned());
+    while let Some(name) = next.take() {
+        if sections.contains_key(&name) {
+            bail!(Error::ConfigParse {
+                message: format!("profile `{}` inherits from itself", name),
+                source: None,
+            });
+        }
+
+        let section = profiles.get(&name).cloned();
+        next = match section.as_ref().and_then(|s| s.get("inherits")) {
+            Some(Value::String(parent)) => Some(parent.clone()),
+            Some(_) => bail!(Error::ConfigParse {
+                message: format!("profile.{}.inherits must be a string", name),
+                source: None,
+            }),
+            None => match &*name {
+                "dev" | "release" => None,
+                "test" => Some("dev".to_owned()),
+                "bench" => Some("release".to_owned()),
+                _ if section.is_some() => {
+                    bail!(
+                        "profile `{}` must set `inherits`, like `inherits = \"release\"`",
+                        name
+                    )
+                }
+                _ => bail!("profile `{}` is not defined", name),
+            },
+        };
+        sections.insert(
+            name,
+            section.unwrap_or_else(|| Value::Table(Default::default())),
+        );
+    }
+
+    Ok(Profile {
+        name: name.to_owned(),
+        sections,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml(s: &str) -> Value {
+        s.parse().unwrap()
+    }
+
+    fn cfg(cfgs: &[&str]) -> Result<Vec<Cfg>> {
+        Ok(cfgs.iter().map(|c| c.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn string_list_accepts_strings_and_arrays() {
+        let value = toml(r#"a = " -C  opt-level=3 --cfg foo ""#);
+        assert_eq!(
+            string_list(&value["a"], "a").unwrap(),
+            ["-C", "opt-level=3", "--cfg", "foo"]
+        );
+
+        let value = toml(r#"a = ["--cfg", "with space"]"#);
+        assert_eq!(
+            string_list(&value["a"], "a").unwrap(),
+            ["--cfg", "with space"]
+        );
+
+        let value = toml("a = []");
+        assert!(string_list(&value["a"], "a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn string_list_rejects_other_values() {
+        for value in &["a = 1", "a = [1]", r#"a = ["x", 1]"#, "a = { b = 1 }"] {
+            let value = toml(value);
+            let err = string_list(&value["a"], "build.rustflags").unwrap_err();
+            assert!(err.to_string().contains("build.rustflags"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn target_flags_joins_triple_and_matching_cfg_keys() {
+        let table = toml(
+            r#"
+            [x86_64-unknown-linux-gnu]
+            rustflags = ["--cfg", "triple"]
+
+            ['cfg(unix)']
+            rustflags = "--cfg unix"
+
+            ['cfg(target_os = "linux")']
+            rustflags = ["--cfg", "linux"]
+
+            ['cfg(windows)']
+            rustflags = ["--cfg", "windows"]
+
+            [aarch64-unknown-none]
+            rustflags = ["--cfg", "other"]
+            "#,
+        );
+        let flags = target_flags(
+            table.as_table().unwrap(),
+            "x86_64-unknown-linux-gnu",
+            "rustflags",
+            || cfg(&["unix", r#"target_os="linux""#]),
+        )
+        .unwrap()
+        .unwrap();
+
+        // the triple comes first, the `cfg(..)` keys follow in the order of the table
+        assert_eq!(&flags[..2], ["--cfg", "triple"]);
+        let mut cfg_flags = flags[2..]
+            .chunks(2)
+            .map(|c| c[1].clone())
+            .collect::<Vec<_>>();
+        cfg_flags.sort();
+        assert_eq!(cfg_flags, ["linux", "unix"]);
+    }
+
+    #[test]
+    fn target_flags_without_matching_keys() {
+        let table = toml(
+            r#"
+            ['cfg(windows)']
+            rustflags = ["--cfg", "windows"]
+
+            ['cfg(unix)']
+            linker = "cc"
+            "#,
+        );
+        let flags = target_flags(
+            table.as_table().unwrap(),
+            "x86_64-unknown-linux-gnu",
+            "rustflags",
+            || cfg(&["unix"]),
+        )
+        .unwrap();
+        assert_eq!(flags, None);
+
+        // the target's cfg isn't needed without `cfg(..)` keys
+        let table = toml(
+            r#"
+            [x86_64-unknown-linux-gnu]
+            rustflags = "--cfg triple"
+            "#,
+        );
+        let flags = target_flags(
+            table.as_table().unwrap(),
+            "x86_64-unknown-linux-gnu",
+            "rustflags",
+            || panic!("cfg requested"),
+        )
+        .unwrap();
+        assert_eq!(flags.unwrap(), ["--cfg", "triple"]);
+    }
+
+    #[test]
+    fn merge_overrides_values_and_concatenates_arrays() {
+        let mut config = Config {
+            table: toml(
+                r#"
+                [build]
+                target = "a"
+                rustflags = ["--cfg", "low"]
+
+                [net]
+                offline = true
+                "#,
+            ),
+            target_base: PathBuf::from("/low"),
+        };
+        config.merge(
+            toml(
+                r#"
+                [build]
+                rustflags = ["--cfg", "high"]
+                rustdocflags = ["--cfg", "doc"]
+
+                [net]
+                offline = false
+                "#,
+            ),
+            Path::new("/high"),
+        );
+
+        assert_eq!(config.table["build"]["target"].as_str(), Some("a"));
+        assert_eq!(
+            string_list(&config.table["build"]["rustflags"], "").unwrap(),
+            ["--cfg", "low", "--cfg", "high"]
+        );
+        assert_eq!(
+            string_list(&config.table["build"]["rustdocflags"], "").unwrap(),
+            ["--cfg", "doc"]
+        );
+        assert!(!config.offline());
+        // relative targets are resolved against the layer that sets `build.target`
+        assert_eq!(config.target_base, Path::new("/low"));
+
+        config.merge(toml(r#"build.target = ["b", "c"]"#), Path::new("/high"));
+        assert_eq!(config.target_base, Path::new("/high"));
+        assert_eq!(config.targets().unwrap(), ["b", "c"]);
+    }
+
+    fn profile_of(manifest: &str, config: Option<&str>, name: &str) -> Result<Profile> {
+        let config = config.map(|config| Config {
+            table: toml(config),
+            target_base: PathBuf::new(),
+        });
+        profile(
+            &Toml {
+                table: toml(manifest),
+            },
+            config.as_ref(),
+            name,
+        )
+    }
+
+    #[test]
+    fn profile_follows_inherits() {
+        let profile = profile_of("", None, "dev").unwrap();
+        assert_eq!(profile.dir_name(), "debug");
+        assert_eq!(profile.fingerprint(), None);
+
+        let profile = profile_of("profile.dev.opt-level = 1", None, "test").unwrap();
+        assert_eq!(profile.dir_name(), "debug");
+        assert_eq!(profile.sections.keys().collect::<Vec<_>>(), ["dev", "test"]);
+
+        let manifest = r#"
+            [profile.release]
+            opt-level = "s"
+            lto = true
+
+            [profile.kernel]
+            inherits = "release"
+            debug = true
+        "#;
+        let profile = profile_of(manifest, None, "kernel").unwrap();
+        assert_eq!(profile.name(), "kernel");
+        assert_eq!(profile.dir_name(), "kernel");
+        assert_eq!(
+            profile.sections.keys().collect::<Vec<_>>(),
+            ["kernel", "release"]
+        );
+        // `lto` doesn't affect the sysroot
+        let fingerprint = profile.fingerprint().unwrap();
+        assert!(!fingerprint.contains("lto"), "{}", fingerprint);
+        assert!(fingerprint.contains("opt-level"), "{}", fingerprint);
+    }
+
+    #[test]
+    fn profile_merges_the_cargo_configuration() {
+        let manifest = r#"
+            [profile.release]
+            opt-level = 3
+        "#;
+        let config = r#"
+            [profile.release]
+            opt-level = "z"
+
+            [profile.small]
+            inherits = "release"
+        "#;
+        let profile = profile_of(manifest, Some(config), "small").unwrap();
+        assert_eq!(profile.sections["release"]["opt-level"].as_str(), Some("z"));
+    }
+
+    #[test]
+    fn profile_errors() {
+        let cycle = r#"
+            [profile.a]
+            inherits = "b"
+
+            [profile.b]
+            inherits = "a"
+        "#;
+        let err = profile_of(cycle, None, "a").err().unwrap();
+        assert!(err.to_string().contains("inherits from itself"), "{}", err);
+        assert!(err.downcast_ref::<Error>().is_some());
+
+        let err = profile_of("profile.a.inherits = \"a\"", None, "a")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("inherits from itself"), "{}", err);
+
+        let err = profile_of("profile.a.inherits = 1", None, "a")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("must be a string"), "{}", err);
+
+        let err = profile_of("profile.a.debug = true", None, "a")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("must set `inherits`"), "{}", err);
+
+        let err = profile_of("", None, "missing").err().unwrap();
+        assert!(err.to_string().contains("is not defined"), "{}", err);
+    }
+
+    #[test]
+    fn missing_target_json() {
+        let config = Config {
+            table: toml(r#"build.target = "missing.json""#),
+            target_base: PathBuf::from("/nonexistent"),
+        };
+        let err = config.targets().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::TargetNotFound { .. })
+        ));
+        assert!(format!("{:#}", err)
+            .contains("target JSON file /nonexistent/missing.json does not exist"));
+        assert!(err.root_cause().downcast_ref::<std::io::Error>().is_some());
+    }
+}
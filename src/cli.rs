@@ -1,12 +1,20 @@
 use std::env;
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 pub struct Args {
     all: Vec<String>,
-    target: Option<String>,
+    targets: Vec<String>,
     manifest_path: Option<PathBuf>,
+    packages: Vec<String>,
+    workspace: bool,
+    exclude: Vec<String>,
+    cargo_config: Vec<String>,
+    offline: bool,
+    locked: bool,
+    profile: Option<String>,
+    json: bool,
     verbosity: Option<Verbosity>,
 }
 
@@ -52,6 +60,14 @@ impl Args {
             ));
         }
 
+        let (packages, workspace) = packages(&other_args);
+        let exclude = exclude(&other_args);
+        let cargo_config = cargo_config(&other_args);
+        let offline = offline(&other_args);
+        let locked = locked(&other_args);
+        let profile = profile(&other_args);
+        let json = json(&other_args);
+
         // add the explicit args to `all` which will be passed on to `cargo`
         let mut all = other_args;
         if let Some(target) = target.clone() {
@@ -72,8 +88,16 @@ impl Args {
 
         Ok(Args {
             all,
-            target: target.map(Into::into),
+            targets: target.map(Into::into).into_iter().collect(),
             manifest_path: manifest_path.map(|p| p.as_ref().into()),
+            packages,
+            workspace,
+            exclude,
+            cargo_config,
+            offline,
+            locked,
+            profile,
+            json,
             verbosity,
         })
     }
@@ -89,21 +113,21 @@ impl Args {
             .map(|a| a.as_ref().to_string())
             .collect::<Vec<_>>();
 
-        let mut target: Option<String> = None;
+        let mut targets = vec![];
         let mut manifest_path = None;
         let mut verbosity = None;
         {
             let mut args = all.iter();
             while let Some(arg) = args.next() {
                 if arg == "--target" {
-                    target = args.next().map(|s| s.to_owned());
+                    targets.extend(args.next().map(|s| s.to_owned()));
                 } else if arg.starts_with("--target=") {
-                    target = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
+                    targets.extend(arg.split_once('=').map(|(_, t)| t.to_owned()));
                 }
                 if arg == "--manifest-path" {
                     manifest_path = args.next().map(|s| s.to_owned());
                 } else if arg.starts_with("--manifest-path=") {
-                    manifest_path = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
+                    manifest_path = arg.split_once('=').map(|(_, t)| t.to_owned());
                 }
                 if arg == "--verbose" || arg == "-v" || arg == "-vv" {
                     if let Some(Verbosity::Quiet) = verbosity {
@@ -120,10 +144,26 @@ impl Args {
             }
         }
 
+        let (packages, workspace) = packages(&all);
+        let exclude = exclude(&all);
+        let cargo_config = cargo_config(&all);
+        let offline = offline(&all);
+        let locked = locked(&all);
+        let profile = profile(&all);
+        let json = json(&all);
+
         Ok(Args {
             all,
-            target: target.map(Into::into),
+            targets,
             manifest_path: manifest_path.map(Into::into),
+            packages,
+            workspace,
+            exclude,
+            cargo_config,
+            offline,
+            locked,
+            profile,
+            json,
             verbosity,
         })
     }
@@ -132,12 +172,82 @@ impl Args {
         &self.all
     }
 
+    /// The last target passed through `--target`
     pub fn target(&self) -> Option<&str> {
-        self.target.as_ref().map(|s| &**s)
+        self.targets.last().map(|s| &**s)
+    }
+
+    /// All targets passed through `--target`, in order
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    /// Returns all args with the `--target` args replaced by the given targets
+    pub fn all_for_targets(&self, targets: &[&str]) -> Vec<String> {
+        let mut all = vec![];
+
+        let mut args = self.all.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                all.push(arg.clone());
+                all.extend(args.by_ref().cloned());
+            } else if arg == "--target" {
+                args.next();
+            } else if !arg.starts_with("--target=") {
+                all.push(arg.clone());
+            }
+        }
+
+        let position = all.iter().position(|a| a == "--").unwrap_or(all.len());
+        all.splice(
+            position..position,
+            targets.iter().map(|t| format!("--target={}", t)),
+        );
+        all
     }
 
     pub fn manifest_path(&self) -> Option<&Path> {
-        self.manifest_path.as_ref().map(|s| &**s)
+        self.manifest_path.as_deref()
+    }
+
+    /// The packages selected through `-p`/`--package`
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+
+    /// Whether all workspace members are selected through `--workspace`/`--all`
+    pub fn workspace(&self) -> bool {
+        self.workspace
+    }
+
+    /// The packages excluded from `--workspace` through `--exclude`
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    /// The `--config` overrides for the Cargo configuration
+    pub fn cargo_config(&self) -> &[String] {
+        &self.cargo_config
+    }
+
+    /// Whether `--offline` or `--frozen` is passed, which both forbid network access
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Whether `--locked` or `--frozen` is passed, which both forbid changes to the lockfile
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// The profile selected through `--release`, `--debug` or `--profile`, if any
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Whether a JSON message format is selected through `--message-format`
+    pub fn json(&self) -> bool {
+        self.json
     }
 
     pub fn quiet(&self) -> bool {
@@ -149,6 +259,133 @@ impl Args {
     }
 }
 
+/// Returns the packages selected through `-p`/`--package` and whether `--workspace`/`--all`
+/// is passed
+fn packages(all: &[String]) -> (Vec<String>, bool) {
+    let mut packages = vec![];
+    let mut workspace = false;
+
+    let mut args = all.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "-p" || arg == "--package" {
+            packages.extend(args.next().cloned());
+        } else if let Some(package) = arg.strip_prefix("--package=") {
+            packages.push(package.to_owned());
+        } else if let Some(package) = arg.strip_prefix("-p") {
+            packages.push(package.to_owned());
+        } else if arg == "--workspace" || arg == "--all" {
+            workspace = true;
+        }
+    }
+
+    (packages, workspace)
+}
+
+/// Returns the values of the `--exclude` arguments
+fn exclude(all: &[String]) -> Vec<String> {
+    let mut exclude = vec![];
+
+    let mut args = all.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--exclude" {
+            exclude.extend(args.next().cloned());
+        } else if let Some(value) = arg.strip_prefix("--exclude=") {
+            exclude.push(value.to_owned());
+        }
+    }
+
+    exclude
+}
+
+/// Returns the values of the `--config` arguments
+fn cargo_config(all: &[String]) -> Vec<String> {
+    let mut config = vec![];
+
+    let mut args = all.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            config.extend(args.next().cloned());
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            config.push(value.to_owned());
+        }
+    }
+
+    config
+}
+
+/// Returns whether `--offline` or `--frozen` is passed
+fn offline(all: &[String]) -> bool {
+    all.iter()
+        .take_while(|a| *a != "--")
+        .any(|a| a == "--offline" || a == "--frozen")
+}
+
+/// Returns whether `--locked` or `--frozen` is passed
+fn locked(all: &[String]) -> bool {
+    all.iter()
+        .take_while(|a| *a != "--")
+        .any(|a| a == "--locked" || a == "--frozen")
+}
+
+/// Returns the profile selected through `--release`/`-r`, `--debug` (of `cargo install`) or
+/// `--profile`
+fn profile(all: &[String]) -> Option<String> {
+    let mut profile = None;
+
+    let mut args = all.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--release" || arg == "-r" {
+            profile = Some("release".to_owned());
+        } else if arg == "--debug" {
+            profile = Some("dev".to_owned());
+        } else if arg == "--profile" {
+            profile = args.next().cloned();
+        } else if let Some(name) = arg.strip_prefix("--profile=") {
+            profile = Some(name.to_owned());
+        }
+    }
+
+    profile
+}
+
+/// Returns whether one of the `--message-format` arguments selects a JSON format, e.g.
+/// `json` or `json-render-diagnostics`
+fn json(all: &[String]) -> bool {
+    let mut formats = vec![];
+
+    let mut args = all.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--message-format" {
+            formats.extend(args.next().cloned());
+        } else if let Some(format) = arg.strip_prefix("--message-format=") {
+            formats.push(format.to_owned());
+        }
+    }
+
+    formats
+        .iter()
+        .flat_map(|format| format.split(','))
+        .any(|format| format.starts_with("json"))
+}
+
+/// Returns the profile that cargo uses for the subcommand `command_name` if none is selected
+pub fn default_profile(command_name: &str) -> &'static str {
+    match command_name {
+        "install" | "bench" => "release",
+        _ => "dev",
+    }
+}
+
 pub fn args(command_name: &str) -> Result<(Command, Args)> {
     let mut args = env::args().skip(1);
     if args.next() != Some("x".to_string() + command_name) {
@@ -161,6 +398,24 @@ pub fn args(command_name: &str) -> Result<(Command, Args)> {
     let command = match all.first().map(|s| s.as_str()) {
         Some("-h") | Some("--help") => Command::Help,
         Some("-v") | Some("--version") => Command::Version,
+        _ if command_name == "clean" => Command::Clean {
+            stale: all
+                .iter()
+                .take_while(|a| *a != "--")
+                .any(|a| a == "--stale"),
+        },
+        _ if command_name == "sysroot" => {
+            let print = print(&all)?;
+            let export = value(&all, "--export");
+            let import = value(&all, "--import");
+            match (print, export, import) {
+                (Some(print), None, None) => Command::Print(print),
+                (None, Some(path), None) => Command::Export(path.into()),
+                (None, None, Some(path)) => Command::Import(path.into()),
+                (None, None, None) => Command::Sysroot,
+                _ => bail!("only one of --print, --export and --import can be passed"),
+            }
+        }
         _ => Command::Build,
     };
 
@@ -168,9 +423,125 @@ pub fn args(command_name: &str) -> Result<(Command, Args)> {
     Ok((command, args))
 }
 
+/// Returns the value of the last `flag` argument, passed as `flag <value>` or `flag=<value>`
+fn value<'a>(all: &'a [String], flag: &str) -> Option<&'a str> {
+    let mut value = None;
+
+    let mut args = all.iter();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            value = args.next().map(|s| &**s);
+        } else if let Some(v) = arg.strip_prefix(flag).and_then(|v| v.strip_prefix('=')) {
+            value = Some(v);
+        }
+    }
+
+    value
+}
+
+/// Returns the value of the `--print` argument
+fn print(all: &[String]) -> Result<Option<Print>> {
+    value(all, "--print")
+        .map(|value| match value {
+            "sysroot" => Ok(Print::Sysroot),
+            "rustflags" => Ok(Print::Rustflags),
+            "env" => Ok(Print::Env),
+            "env-json" => Ok(Print::EnvJson),
+            "target" => Ok(Print::Target),
+            _ => Err(anyhow!(
+                "unknown value `{}` for --print; possible values are \
+                 sysroot, rustflags, env, env-json and target",
+                value
+            )),
+        })
+        .transpose()
+}
+
 #[derive(Clone, PartialEq)]
 pub enum Command {
     Build,
+    Sysroot,
+    Print(Print),
+    /// Build the sysroot and write it to the given archive
+    Export(PathBuf),
+    /// Unpack the sysroot from the given archive instead of building it
+    Import(PathBuf),
+    /// Remove the sysroots, or only those built by a different toolchain if `stale` is set
+    Clean {
+        stale: bool,
+    },
     Help,
     Version,
 }
+
+/// What to print instead of building the sysroot
+#[derive(Clone, Copy, PartialEq)]
+pub enum Print {
+    /// The path of the sysroot
+    Sysroot,
+    /// The rustflags that cargo is run with
+    Rustflags,
+    /// The environment variables that cargo is run with, as shell `export` lines
+    Env,
+    /// The environment variables that cargo is run with, as a JSON object
+    EnvJson,
+    /// The targets to build for
+    Target,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(all: &[&str]) -> Args {
+        Args::from_raw(all).unwrap()
+    }
+
+    #[test]
+    fn all_for_targets_replaces_the_targets() {
+        let args = args(&["--target", "a", "-p", "foo", "--target=b", "--release"]);
+        assert_eq!(args.targets(), ["a", "b"]);
+        assert_eq!(
+            args.all_for_targets(&["b"]),
+            ["-p", "foo", "--release", "--target=b"]
+        );
+        assert_eq!(
+            args.all_for_targets(&["a", "b"]),
+            ["-p", "foo", "--release", "--target=a", "--target=b"]
+        );
+    }
+
+    #[test]
+    fn all_for_targets_keeps_the_args_after_double_dash() {
+        let args = args(&["--target=a", "--bin", "x", "--", "--target", "c"]);
+        assert_eq!(
+            args.all_for_targets(&["a"]),
+            ["--bin", "x", "--target=a", "--", "--target", "c"]
+        );
+    }
+
+    #[test]
+    fn all_for_targets_without_targets() {
+        let args = args(&["--release"]);
+        assert!(args.targets().is_empty());
+        assert_eq!(args.all_for_targets(&["a"]), ["--release", "--target=a"]);
+        assert_eq!(args.all_for_targets(&[]), ["--release"]);
+    }
+
+    #[test]
+    fn selected_profile() {
+        assert_eq!(args(&[]).profile(), None);
+        assert_eq!(args(&["-r"]).profile(), Some("release"));
+        assert_eq!(args(&["--debug"]).profile(), Some("dev"));
+        assert_eq!(
+            args(&["--release", "--profile=custom"]).profile(),
+            Some("custom")
+        );
+        assert_eq!(args(&["--", "--release"]).profile(), None);
+
+        assert_eq!(default_profile("build"), "dev");
+        assert_eq!(default_profile("test"), "dev");
+        assert_eq!(default_profile("install"), "release");
+        assert_eq!(default_profile("bench"), "release");
+    }
+}
@@ -1,13 +1,73 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use anyhow::{anyhow, bail, Result};
+use cargo_metadata::{Metadata, Package};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-#[derive(Debug, Hash)]
+use crate::{util, Error};
+
+/// The file in the workspace root that can hold the config instead of the
+/// `[workspace.metadata.cargo-xbuild]` table
+const XBUILD_TOML: &str = "Xbuild.toml";
+
+/// The cargo-xbuild configuration
+///
+/// It is read from the `[package.metadata.cargo-xbuild]` and `[workspace.metadata.cargo-xbuild]`
+/// tables or from an `Xbuild.toml` file, and it can be built with [`Config::builder`] or by
+/// overriding fields of [`Config::default`]. More fields may be added in the future, so it
+/// can't be constructed with a struct expression outside of this crate.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(try_from = "ParseConfig")]
+#[non_exhaustive]
 pub struct Config {
     pub memcpy: bool,
     pub sysroot_path: PathBuf,
     pub panic_immediate_abort: bool,
+    pub crates: Vec<String>,
+    /// Additional features to enable for each sysroot crate
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Patch files to apply to the Rust source before building the sysroot, as glob patterns
+    /// relative to the workspace root
+    pub patches: Vec<String>,
+    /// Overrides for registry dependencies of the sysroot crates, e.g. `compiler_builtins`
+    pub dependencies: BTreeMap<String, Dependency>,
+    /// A directory with the vendored registry dependencies of the sysroot crates, relative to
+    /// the workspace root
+    pub vendor: Option<PathBuf>,
+}
+
+/// Where to take a registry dependency of the sysroot crates from instead of the version in
+/// the lockfile of the Rust source
+///
+/// Exactly one of `version`, `git` and `path` must be set.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dependency {
+    /// The exact version to lock the dependency to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    /// A local checkout, relative to the workspace root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+/// A dependency override is either a version or a table like in `[dependencies]`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ParseDependency {
+    Version(String),
+    Detailed(Dependency),
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -15,53 +75,558 @@ struct ParseConfig {
     pub memcpy: Option<bool>,
     pub sysroot_path: Option<String>,
     pub panic_immediate_abort: Option<bool>,
+    pub crates: Option<Vec<String>>,
+    pub features: Option<BTreeMap<String, Vec<String>>>,
+    pub patches: Option<Vec<String>>,
+    pub dependencies: Option<BTreeMap<String, ParseDependency>>,
+    pub vendor: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            memcpy: true,
+            sysroot_path: PathBuf::from("target/sysroot"),
+            panic_immediate_abort: false,
+            crates: vec!["core".into(), "compiler_builtins".into(), "alloc".into()],
+            features: BTreeMap::new(),
+            patches: vec![],
+            dependencies: BTreeMap::new(),
+            vendor: None,
+        }
+    }
+}
+
+impl TryFrom<ParseConfig> for Config {
+    type Error = Error;
+
+    fn try_from(config: ParseConfig) -> Result<Config, Error> {
+        let default = Config::default();
+
+        let mut dependencies = BTreeMap::new();
+        for (name, dependency) in config.dependencies.unwrap_or_default() {
+            let dependency = match dependency {
+                ParseDependency::Version(version) => Dependency {
+                    version: Some(version),
+                    ..Dependency::default()
+                },
+                ParseDependency::Detailed(dependency) => dependency,
+            };
+            dependency.validate(&name)?;
+            dependencies.insert(name, dependency);
+        }
+
+        Ok(Config {
+            memcpy: config.memcpy.unwrap_or(default.memcpy),
+            sysroot_path: config
+                .sysroot_path
+                .map(PathBuf::from)
+                .unwrap_or(default.sysroot_path),
+            panic_immediate_abort: config
+                .panic_immediate_abort
+                .unwrap_or(default.panic_immediate_abort),
+            crates: config.crates.unwrap_or(default.crates),
+            features: config.features.unwrap_or_default(),
+            patches: config.patches.unwrap_or_default(),
+            dependencies,
+            vendor: config.vendor.map(PathBuf::from),
+        })
+    }
+}
+
+impl Dependency {
+    /// Checks that the override of the dependency `name` has exactly one source
+    fn validate(&self, name: &str) -> Result<(), Error> {
+        let sources = [
+            self.version.is_some(),
+            self.git.is_some(),
+            self.path.is_some(),
+        ];
+        if sources.iter().filter(|set| **set).count() != 1 {
+            return Err(Error::ConfigParse {
+                message: format!(
+                    "the override of `{}` must set exactly one of `version`, `git` and `path`",
+                    name
+                ),
+                source: None,
+            });
+        }
+        if self.git.is_none() && (self.branch.is_some() || self.tag.is_some() || self.rev.is_some())
+        {
+            return Err(Error::ConfigParse {
+                message: format!(
+                    "the override of `{}` sets `branch`, `tag` or `rev` without `git`",
+                    name
+                ),
+                source: None,
+            });
+        }
+
+        Ok(())
+    }
 }
 
 impl Config {
-    pub fn from_metadata(metadata: &cargo_metadata::Metadata, quiet: bool) -> Result<Config> {
+    /// Returns a builder that starts from the default config
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+        }
+    }
+
+    /// Parses the config from TOML with the keys of the `[package.metadata.cargo-xbuild]`
+    /// table, e.g. the contents of an `Xbuild.toml` file
+    pub fn from_toml_str(toml: &str) -> Result<Config> {
+        toml::from_str(toml).map_err(|e| {
+            Error::ConfigParse {
+                message: "parsing the cargo-xbuild config failed".to_owned(),
+                source: Some(e.into()),
+            }
+            .into()
+        })
+    }
+
+    /// Reads the config for building the package of the manifest at `manifest_path`, or all
+    /// workspace members for a virtual manifest
+    pub fn from_manifest_path(manifest_path: &Path) -> Result<Config> {
+        let metadata = cargo_metadata::MetadataCommand::new()
+            .manifest_path(manifest_path)
+            .exec()
+            .map_err(|e| anyhow!("cargo metadata invocation failed: {}", e))?;
+        Config::from_workspace(&metadata, &[], &[], manifest_path)
+    }
+
+    /// Reads the config for building the root package of the workspace
+    ///
+    /// If the workspace has no root package, the config for building all workspace members
+    /// is read instead.
+    pub fn from_metadata(metadata: &Metadata, _quiet: bool) -> Result<Config> {
         let root_manifest = metadata.workspace_root.join("Cargo.toml");
-        let root_package = metadata
+        Config::from_workspace(metadata, &[], &[], &root_manifest)
+    }
+
+    /// Reads the config for building the given workspace packages
+    ///
+    /// The `[workspace.metadata.cargo-xbuild]` table or the `Xbuild.toml` file in the workspace
+    /// root, which can't be used together, is read first. The
+    /// `[package.metadata.cargo-xbuild]` tables of the built packages take precedence over it.
+    /// The built packages are the `packages` passed through `-p`/`--package` or, if there are
+    /// none, the package of the manifest at `manifest_path`. For a virtual manifest, all
+    /// workspace members are built. Packages matching one of the `exclude` patterns, which
+    /// are passed through `--exclude` and may contain globs, are not built.
+    ///
+    /// All built packages share one sysroot, so it is an error if two of them set different
+    /// values for the same option.
+    pub fn from_workspace(
+        metadata: &Metadata,
+        packages: &[String],
+        exclude: &[String],
+        manifest_path: &Path,
+    ) -> Result<Config> {
+        let mut config = match metadata.workspace_metadata.get("cargo-xbuild") {
+            Some(Value::Object(map)) => map.clone(),
+            Some(_) => bail!(Error::ConfigParse {
+                message: "workspace.metadata.cargo-xbuild must be a table".to_owned(),
+                source: None,
+            }),
+            None => Map::new(),
+        };
+
+        let xbuild_toml = Path::new(&metadata.workspace_root).join(XBUILD_TOML);
+        if xbuild_toml.is_file() {
+            if !config.is_empty() {
+                bail!(Error::ConfigParse {
+                    message: format!(
+                        "both {} and workspace.metadata.cargo-xbuild exist; move the config \
+                         into one of them",
+                        xbuild_toml.display()
+                    ),
+                    source: None,
+                });
+            }
+            config = match toml::from_str(&util::read(&xbuild_toml)?) {
+                Ok(Value::Object(map)) => map,
+                Ok(_) => unreachable!("TOML documents are tables"),
+                Err(e) => bail!(Error::ConfigParse {
+                    message: format!("parsing {} failed", xbuild_toml.display()),
+                    source: Some(e.into()),
+                }),
+            };
+        }
+
+        let members = metadata
             .packages
             .iter()
-            .find(|package| package.manifest_path == root_manifest);
-
-        let config = match root_package {
-            Some(root_package) => {
-                let crate_metadata = root_package.metadata.get("cargo-xbuild");
-                match crate_metadata {
-                    Some(json) => serde_json::from_value(json.clone()).map_err(|e| {
-                        anyhow!(
-                            "parsing package.metadata.cargo-xbuild section failed: {}",
-                            e
-                        )
-                    })?,
-                    None => ParseConfig::default(),
-                }
+            .filter(|p| metadata.workspace_members.contains(&p.id))
+            .collect::<Vec<_>>();
+        let exclude = exclude
+            .iter()
+            .map(|spec| {
+                let name = spec.split([':', '@']).next().unwrap();
+                glob::Pattern::new(name)
+                    .map_err(|e| anyhow!("invalid --exclude pattern `{}`: {}", spec, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut selected = if !packages.is_empty() {
+            packages
+                .iter()
+                .map(|spec| {
+                    // `-p` takes a package ID spec such as `foo`, `foo:1.0.0` or `foo@1.0.0`
+                    let name = spec.split([':', '@']).next().unwrap();
+                    members
+                        .iter()
+                        .find(|p| p.name == name)
+                        .copied()
+                        .ok_or_else(|| anyhow!("package `{}` is not a workspace member", spec))
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            let manifest_path = manifest_path
+                .canonicalize()
+                .unwrap_or_else(|_| manifest_path.to_owned());
+            match members
+                .iter()
+                .find(|p| p.manifest_path.canonicalize().ok().as_ref() == Some(&manifest_path))
+            {
+                Some(package) => vec![*package],
+                None => members,
             }
-            None => {
-                // The project has no root package. This could be because it only defines a
-                // dummy `Cargo.toml` at the root without a `[package]` section.
-                //
-                // The problem in this case is that we don't have a reasonable place to read
-                // the config from. There are multiple `Cargo.toml`s in this workspace and it
-                // is not clear which one is the "canonical" Cargo.toml with the xbuild config.
-                //
-                // So we can't read the config for such projects. To make this transparent to
-                // the user, we print a warning.
-                if !quiet {
-                    eprintln!(
-                        "WARNING: There is no root package to read the cargo-xbuild config from."
-                    );
+        };
+        selected.retain(|p| !exclude.iter().any(|pattern| pattern.matches(&p.name)));
+
+        let mut package_config = Map::new();
+        let mut set_by: BTreeMap<String, &Package> = BTreeMap::new();
+        for package in selected {
+            let map = match package.metadata.get("cargo-xbuild") {
+                Some(Value::Object(map)) => map,
+                Some(_) => bail!(Error::ConfigParse {
+                    message: format!(
+                        "package.metadata.cargo-xbuild of package `{}` must be a table",
+                        package.name
+                    ),
+                    source: None,
+                }),
+                None => continue,
+            };
+            for (key, value) in map {
+                if let Some(previous) = package_config.get(key) {
+                    if previous != value {
+                        bail!(Error::ConfigParse {
+                            message: format!(
+                                "packages `{}` and `{}` set different values for \
+                                 `package.metadata.cargo-xbuild.{}`, but they are built \
+                                 with the same sysroot",
+                                set_by[key].name, package.name, key
+                            ),
+                            source: None,
+                        });
+                    }
+                } else {
+                    package_config.insert(key.clone(), value.clone());
+                    set_by.insert(key.clone(), package);
                 }
-                // There is no config to read, so we use default options
-                ParseConfig::default()
             }
-        };
+        }
+        config.extend(package_config);
 
-        Ok(Config {
-            memcpy: config.memcpy.unwrap_or(true),
-            sysroot_path: PathBuf::from(config.sysroot_path.unwrap_or("target/sysroot".into())),
-            panic_immediate_abort: config.panic_immediate_abort.unwrap_or(false),
+        serde_json::from_value(Value::Object(config)).map_err(|e| {
+            Error::ConfigParse {
+                message: "parsing the cargo-xbuild config failed".to_owned(),
+                source: Some(e.into()),
+            }
+            .into()
         })
     }
+
+    /// Returns the patch files matched by the `patches` patterns, relative to `root`
+    ///
+    /// The files of each pattern are sorted by name. It is an error if a pattern matches no
+    /// files, since that is most likely a typo.
+    pub fn patch_files(&self, root: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for pattern in &self.patches {
+            let absolute = root.join(pattern);
+            let matches = glob::glob(&absolute.to_string_lossy())
+                .map_err(|e| Error::ConfigParse {
+                    message: format!("invalid patch pattern `{}`", pattern),
+                    source: Some(e.into()),
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            if matches.is_empty() {
+                bail!("the patch pattern `{}` matches no files", pattern);
+            }
+
+            for path in matches {
+                let path = path.strip_prefix(root).unwrap_or(&path).to_owned();
+                if !files.contains(&path) {
+                    files.push(path);
+                }
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+/// Builds a [`Config`], starting from the defaults
+///
+/// ```
+/// let config = xargo_lib::Config::builder()
+///     .crates(["core", "alloc"])
+///     .features("core", ["panic_immediate_abort"])
+///     .build()
+///     .unwrap();
+/// assert!(config.memcpy);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Whether the `mem` feature of `compiler_builtins` is enabled. Defaults to `true`.
+    pub fn memcpy(mut self, memcpy: bool) -> Self {
+        self.config.memcpy = memcpy;
+        self
+    }
+
+    /// Where the sysroot is placed, relative to the workspace root. Defaults to
+    /// `target/sysroot`.
+    pub fn sysroot_path(mut self, sysroot_path: impl Into<PathBuf>) -> Self {
+        self.config.sysroot_path = sysroot_path.into();
+        self
+    }
+
+    pub fn panic_immediate_abort(mut self, panic_immediate_abort: bool) -> Self {
+        self.config.panic_immediate_abort = panic_immediate_abort;
+        self
+    }
+
+    /// The crates that are built into the sysroot, instead of `core`, `compiler_builtins` and
+    /// `alloc`
+    pub fn crates<I, S>(mut self, crates: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.crates = crates.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Enables `features` of the sysroot crate `krate`
+    pub fn features<I, S>(mut self, krate: &str, features: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config
+            .features
+            .entry(krate.to_owned())
+            .or_default()
+            .extend(features.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds a glob pattern of patch files, relative to the workspace root
+    pub fn patch(mut self, pattern: impl Into<String>) -> Self {
+        self.config.patches.push(pattern.into());
+        self
+    }
+
+    /// Overrides the registry dependency `name` of the sysroot crates
+    pub fn dependency(mut self, name: &str, dependency: Dependency) -> Self {
+        self.config.dependencies.insert(name.to_owned(), dependency);
+        self
+    }
+
+    /// A directory with the vendored registry dependencies, relative to the workspace root
+    pub fn vendor(mut self, vendor: impl Into<PathBuf>) -> Self {
+        self.config.vendor = Some(vendor.into());
+        self
+    }
+
+    /// Returns the config, or an error if a dependency override is invalid
+    pub fn build(self) -> Result<Config> {
+        for (name, dependency) in &self.config.dependencies {
+            dependency.validate(name)?;
+        }
+
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    /// Writes a workspace with the root `manifest` and the members `a` and `b`, whose
+    /// `[package.metadata.cargo-xbuild]` tables are given, and reads its metadata
+    fn workspace(manifest: &str, a: &str, b: &str) -> (TempDir, Metadata) {
+        let td = TempDir::new().unwrap();
+        util::write(&td.path().join("Cargo.toml"), manifest).unwrap();
+        for (name, config) in [("a", a), ("b", b)] {
+            let dir = td.path().join(name);
+            fs::create_dir_all(dir.join("src")).unwrap();
+            util::write(&dir.join("src").join("lib.rs"), "").unwrap();
+            util::write(
+                &dir.join("Cargo.toml"),
+                &format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.0.0\"\n\n\
+                     [package.metadata.cargo-xbuild]\n{}\n",
+                    name, config
+                ),
+            )
+            .unwrap();
+        }
+
+        let metadata = cargo_metadata::MetadataCommand::new()
+            .manifest_path(td.path().join("Cargo.toml"))
+            .no_deps()
+            .exec()
+            .unwrap();
+        (td, metadata)
+    }
+
+    #[test]
+    fn package_config_takes_precedence() {
+        let manifest = r#"
+            [workspace]
+            members = ["a", "b"]
+
+            [workspace.metadata.cargo-xbuild]
+            memcpy = false
+            sysroot_path = "workspace"
+        "#;
+        let (td, metadata) = workspace(manifest, r#"sysroot_path = "a""#, "");
+
+        let config =
+            Config::from_workspace(&metadata, &[], &[], &td.path().join("a/Cargo.toml")).unwrap();
+        assert_eq!(config.sysroot_path, Path::new("a"));
+        assert!(!config.memcpy);
+
+        let config =
+            Config::from_workspace(&metadata, &[], &[], &td.path().join("b/Cargo.toml")).unwrap();
+        assert_eq!(config.sysroot_path, Path::new("workspace"));
+    }
+
+    #[test]
+    fn conflicting_package_configs() {
+        let manifest = "[workspace]\nmembers = [\"a\", \"b\"]\n";
+        let (td, metadata) = workspace(
+            manifest,
+            r#"crates = ["core"]"#,
+            r#"crates = ["core", "alloc"]"#,
+        );
+        let root_manifest = td.path().join("Cargo.toml");
+
+        // A virtual manifest builds all members
+        let err = Config::from_workspace(&metadata, &[], &[], &root_manifest).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("packages `a` and `b` set different values"),
+            "{}",
+            err
+        );
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ConfigParse { .. })
+        ));
+
+        let packages = ["a".to_owned(), "b@0.0.0".to_owned()];
+        assert!(Config::from_workspace(&metadata, &packages, &[], &root_manifest).is_err());
+    }
+
+    #[test]
+    fn excluded_packages_are_ignored() {
+        let manifest = "[workspace]\nmembers = [\"a\", \"b\"]\n";
+        let (td, metadata) = workspace(manifest, r#"crates = ["core"]"#, "crates = 1");
+        let root_manifest = td.path().join("Cargo.toml");
+
+        for exclude in ["b", "b:0.0.0", "[b-z]"] {
+            let config =
+                Config::from_workspace(&metadata, &[], &[exclude.to_owned()], &root_manifest)
+                    .unwrap();
+            assert_eq!(config.crates, ["core"]);
+        }
+
+        let err =
+            Config::from_workspace(&metadata, &[], &["[".to_owned()], &root_manifest).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid --exclude pattern"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn serialization_round_trips() {
+        let configs = [
+            Config::default(),
+            Config::builder()
+                .memcpy(false)
+                .sysroot_path("sysroot")
+                .panic_immediate_abort(true)
+                .crates(["core", "alloc"])
+                .features("compiler_builtins", ["mem", "no-asm"])
+                .patch("patches/*.patch")
+                .dependency(
+                    "compiler_builtins",
+                    Dependency {
+                        git: Some("https://github.com/rust-lang/compiler-builtins".to_owned()),
+                        rev: Some("abcdef".to_owned()),
+                        ..Dependency::default()
+                    },
+                )
+                .dependency(
+                    "cc",
+                    Dependency {
+                        version: Some("1.0.0".to_owned()),
+                        ..Dependency::default()
+                    },
+                )
+                .vendor("vendor")
+                .build()
+                .unwrap(),
+        ];
+
+        for config in configs {
+            let json = serde_json::to_string(&config).unwrap();
+            assert_eq!(serde_json::from_str::<Config>(&json).unwrap(), config);
+
+            let toml = toml::Value::try_from(&config).unwrap().to_string();
+            assert_eq!(Config::from_toml_str(&toml).unwrap(), config, "{}", toml);
+        }
+    }
+
+    #[test]
+    fn invalid_dependencies_are_rejected() {
+        let configs = [
+            (
+                r#"dependencies.cc = { version = "1.0.0", path = "cc" }"#,
+                "must set exactly one of `version`, `git` and `path`",
+            ),
+            ("dependencies.cc = {}", "must set exactly one"),
+            (
+                r#"dependencies.cc = { path = "cc", branch = "main" }"#,
+                "sets `branch`, `tag` or `rev` without `git`",
+            ),
+        ];
+
+        for (toml, message) in configs {
+            let err = Config::from_toml_str(toml).unwrap_err();
+            assert!(format!("{:#}", err).contains(message), "{:#}", err);
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::ConfigParse { .. })
+            ));
+
+            let json = serde_json::to_value(toml::from_str::<toml::Value>(toml).unwrap()).unwrap();
+            let err = serde_json::from_value::<Config>(json).unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
 }
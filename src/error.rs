@@ -0,0 +1,113 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+/// The causes of failure that library consumers may want to handle
+///
+/// The functions of this crate return an [`anyhow::Error`], which adds context as the error
+/// propagates and prints it as a chain of causes. If the failure has one of the causes below,
+/// the `Error` can be retrieved with [`anyhow::Error::downcast_ref`]:
+///
+/// ```no_run
+/// # let args = xargo_lib::Args::from_raw(Vec::<String>::new()).unwrap();
+/// match xargo_lib::build(args, "build", None) {
+///     Ok(status) => println!("cargo exited with {}", status),
+///     Err(e) => match e.downcast_ref::<xargo_lib::Error>() {
+///         Some(xargo_lib::Error::MissingRustSrc { .. }) => eprintln!("please install rust-src"),
+///         _ => eprintln!("{:?}", e),
+///     },
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Neither the `rust-src` component is installed nor `XARGO_RUST_SRC` is set
+    MissingRustSrc {
+        /// Whether the toolchain is a locally built `dev` toolchain, which has no `rust-src`
+        /// component
+        dev: bool,
+    },
+    /// The sysroot can't be built with a toolchain of this channel, e.g. `Stable`
+    UnsupportedChannel { channel: String },
+    /// The target is neither a built-in target of `rustc` nor a target specification file
+    TargetNotFound { target: String },
+    /// The target specification file isn't valid JSON
+    InvalidTargetJson {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// Cargo failed to build the sysroot
+    SysrootBuildFailed {
+        /// The sysroot crate that failed to compile, or `None` if the failure can't be
+        /// attributed to a single crate, e.g. because a build script failed
+        krate: Option<String>,
+        status: ExitStatus,
+    },
+    /// A sysroot or the sysroot cache couldn't be locked
+    LockFailed { path: PathBuf, source: io::Error },
+    /// The `cargo-xbuild` configuration, the Cargo configuration or a profile is invalid
+    ConfigParse {
+        message: String,
+        /// The error of the parser, if the message is about a value that couldn't be parsed
+        source: Option<Box<dyn StdError + Send + Sync>>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MissingRustSrc { dev: true } => f.write_str(
+                "Rust source for the `dev` toolchain not found. It has no `rust-src` component, so \
+                 point the XARGO_RUST_SRC env variable to the `library` directory of the Rust \
+                 checkout that it was built from.",
+            ),
+            Error::MissingRustSrc { dev: false } => f.write_str(
+                "`rust-src` component not found. Run `rustup component add rust-src` or \
+                 point the XARGO_RUST_SRC env variable to the Rust source directory.",
+            ),
+            Error::UnsupportedChannel { ref channel } => write!(
+                f,
+                "The sysroot can't be built for the {} channel. Switch to nightly.",
+                channel
+            ),
+            Error::TargetNotFound { ref target } => write!(
+                f,
+                "no sysroot can be built for the target `{}`: it is neither a built-in \
+                 target nor a target specification file",
+                target
+            ),
+            Error::InvalidTargetJson { ref path, .. } => {
+                write!(f, "{} is not valid JSON", path.display())
+            }
+            Error::SysrootBuildFailed {
+                krate: Some(ref krate),
+                status,
+            } => write!(
+                f,
+                "building `{}` for the sysroot failed ({})",
+                krate, status
+            ),
+            Error::SysrootBuildFailed { krate: None, status } => {
+                write!(f, "building the sysroot failed ({})", status)
+            }
+            Error::LockFailed { ref path, .. } => write!(f, "couldn't lock {}", path.display()),
+            Error::ConfigParse { ref message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::InvalidTargetJson { ref source, .. } => Some(source),
+            Error::LockFailed { ref source, .. } => Some(source),
+            Error::ConfigParse {
+                source: Some(ref source),
+                ..
+            } => Some(&**source),
+            _ => None,
+        }
+    }
+}
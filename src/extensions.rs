@@ -2,16 +2,18 @@ use std::process::{Command, ExitStatus};
 
 use anyhow::{anyhow, Context, Result};
 
+use crate::message::Messages;
+
 pub trait CommandExt {
-    fn run(&mut self, verbose: bool) -> Result<()>;
-    fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus>;
+    fn run(&mut self, messages: &Messages) -> Result<()>;
+    fn run_and_get_status(&mut self, messages: &Messages) -> Result<ExitStatus>;
     fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String>;
 }
 
 impl CommandExt for Command {
     /// Runs the command to completion
-    fn run(&mut self, verbose: bool) -> Result<()> {
-        let status = self.run_and_get_status(verbose)?;
+    fn run(&mut self, messages: &Messages) -> Result<()> {
+        let status = self.run_and_get_status(messages)?;
 
         if status.success() {
             Ok(())
@@ -25,10 +27,8 @@ impl CommandExt for Command {
     }
 
     /// Runs the command to completion
-    fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus> {
-        if verbose {
-            eprintln!("+ {:?}", self);
-        }
+    fn run_and_get_status(&mut self, messages: &Messages) -> Result<ExitStatus> {
+        messages.observer().command_spawned(self);
 
         self.status()
             .with_context(|| format!("couldn't execute `{:?}`", self))
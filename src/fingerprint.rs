@@ -0,0 +1,523 @@
+//! The fingerprint of a sysroot, which decides whether it has to be rebuilt
+//!
+//! The fingerprint lists every input that affects the compilation of the sysroot crates. Its
+//! hash is the SHA-256 of the fingerprint serialized as compact JSON, with the fields in
+//! declaration order and object keys sorted, so it is the same on every machine and with every
+//! Rust version. The hash is stored in the `.hash` file of the sysroot and the fingerprint
+//! itself in `.fingerprint.json`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use rustc_version::VersionMeta;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::cargo::{Profile, Rustflags};
+use crate::config::Config;
+use crate::rustc::Src;
+use crate::util;
+use crate::CompilationMode;
+
+const FINGERPRINT: &str = ".fingerprint.json";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Fingerprint {
+    /// The condensed target triple
+    triple: String,
+    /// The output of `rustc --version`
+    rustc: String,
+    rustc_commit: Option<String>,
+    /// The rustflags that the sysroot crates are compiled with
+    rustflags: Vec<String>,
+    /// The contents of the target specification file, if any
+    target_spec: Option<Value>,
+    /// The name of the profile that the sysroot is built with
+    profile: String,
+    /// The sections of the profile and the profiles it inherits from, without `lto`, if any
+    profile_settings: Option<String>,
+    /// The cargo-xbuild configuration
+    config: Value,
+    /// The fingerprint of a custom Rust source passed through `XARGO_RUST_SRC`, see
+    /// [`tree_fingerprint`]
+    src: Option<String>,
+    /// The SHA-256 of the `Cargo.lock` of a custom Rust source
+    lockfile: Option<String>,
+    /// The SHA-256 of each patch file that is applied to the Rust source, by path relative to
+    /// the workspace root
+    #[serde(default)]
+    patches: BTreeMap<String, String>,
+    /// The fingerprint of each dependency that is overridden by a local checkout, see
+    /// [`tree_fingerprint`]
+    #[serde(default)]
+    dependencies: BTreeMap<String, String>,
+    /// The commit that each `git` override without a `rev` is locked to, by crate name
+    ///
+    /// The commit is read from the lockfile of the build and kept as long as the override
+    /// stays the same, like Cargo keeps the commits of branches in `Cargo.lock`.
+    #[serde(default)]
+    git_commits: BTreeMap<String, String>,
+}
+
+impl Fingerprint {
+    pub fn new(
+        cmode: &CompilationMode,
+        rustflags: &Rustflags,
+        profile: &Profile,
+        meta: &VersionMeta,
+        config: &Config,
+        src: Option<&Src>,
+        root: &Path,
+    ) -> Result<Fingerprint> {
+        let mut patches = BTreeMap::new();
+        for patch in config.patch_files(root)? {
+            let path = root.join(&patch);
+            let contents =
+                fs::read(&path).with_context(|| format!("couldn't read {}", path.display()))?;
+            patches.insert(patch.display().to_string(), hex(&Sha256::digest(contents)));
+        }
+
+        let mut dependencies = BTreeMap::new();
+        for (name, dependency) in &config.dependencies {
+            if let Some(ref path) = dependency.path {
+                dependencies.insert(name.clone(), tree_fingerprint(&root.join(path))?);
+            }
+        }
+
+        // The `rust-src` component is covered by the `rustc` commit hash
+        let src = src.filter(|src| src.is_custom());
+        let lockfile = match src.map(Src::lockfile) {
+            Some(lockfile) if lockfile.is_file() => Some(hex(&Sha256::digest(
+                fs::read(&lockfile)
+                    .with_context(|| format!("couldn't read {}", lockfile.display()))?,
+            ))),
+            _ => None,
+        };
+
+        // The vendored sources are pinned by the checksums in the lockfile, so their location
+        // doesn't matter. The sysroot path doesn't affect the contents of the sysroot either,
+        // so that workspaces with different ones can share entries of the sysroot cache.
+        let mut config = serde_json::to_value(config)?;
+        if let Value::Object(ref mut map) = config {
+            map.remove("vendor");
+            map.remove("sysroot_path");
+        }
+
+        let target_spec = match *cmode {
+            CompilationMode::Cross(ref target) => target.spec()?,
+            CompilationMode::Native(_) => None,
+        };
+
+        Ok(Fingerprint {
+            triple: cmode.triple().to_owned(),
+            rustc: meta.short_version_string.clone(),
+            rustc_commit: meta.commit_hash.clone(),
+            rustflags: rustflags.for_sysroot(),
+            target_spec,
+            profile: profile.name().to_owned(),
+            profile_settings: profile.fingerprint(),
+            config,
+            src: src.map(|src| tree_fingerprint(src.path())).transpose()?,
+            lockfile,
+            patches,
+            dependencies,
+            git_commits: BTreeMap::new(),
+        })
+    }
+
+    /// Reads the fingerprint that was written to the sysroot directory `dir`, if any
+    pub fn read(dir: &Path) -> Option<Fingerprint> {
+        let path = dir.join(FINGERPRINT);
+        if !path.exists() {
+            return None;
+        }
+
+        util::read(&path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+    }
+
+    /// Writes the fingerprint and its hash to the sysroot directory `dir`
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        util::write(&dir.join(FINGERPRINT), &json)?;
+        util::write(&dir.join(".hash"), &self.hash())
+    }
+
+    pub fn triple(&self) -> &str {
+        &self.triple
+    }
+
+    /// The commits that the `git` overrides without a `rev` are locked to
+    pub fn git_commits(&self) -> &BTreeMap<String, String> {
+        &self.git_commits
+    }
+
+    /// Locks the `git` overrides without a `rev` to the given commits
+    pub fn lock_git_commits(&mut self, commits: BTreeMap<String, String>) {
+        self.git_commits.extend(commits);
+    }
+
+    /// Keeps the commits that `old` locked the `git` overrides to, for the overrides that
+    /// haven't changed since
+    pub fn keep_git_commits(&mut self, old: &Fingerprint) {
+        fn dependency<'a>(fingerprint: &'a Fingerprint, name: &str) -> Option<&'a Value> {
+            fingerprint.config.get("dependencies")?.get(name)
+        }
+
+        for (name, commit) in &old.git_commits {
+            if dependency(self, name).is_some() && dependency(self, name) == dependency(old, name) {
+                self.git_commits.insert(name.clone(), commit.clone());
+            }
+        }
+    }
+
+    /// Returns the hex-encoded SHA-256 of the canonical serialization
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("fingerprints can always be serialized");
+        hex(&Sha256::digest(json))
+    }
+
+    /// Describes how this fingerprint differs from the `old` one, e.g.
+    /// `rustflags changed: added -Ctarget-feature=+soft-float`
+    pub fn changes(&self, old: &Fingerprint) -> Vec<String> {
+        let mut changes = vec![];
+        if let (Ok(old), Ok(new)) = (serde_json::to_value(old), serde_json::to_value(self)) {
+            diff("", &old, &new, &mut changes);
+        }
+
+        changes
+    }
+}
+
+/// Computes the fingerprint of a source tree, e.g. the Rust source
+///
+/// By default, the paths, sizes and modification times of all files are hashed. If the
+/// `XBUILD_SRC_FINGERPRINT` environment variable is set to `content`, the contents of the files
+/// are hashed instead, which is slower but also detects edits that preserve the modification
+/// time. `target` directories and hidden files are skipped.
+fn tree_fingerprint(dir: &Path) -> Result<String> {
+    let content = match env::var("XBUILD_SRC_FINGERPRINT") {
+        Ok(ref mode) if mode == "content" => true,
+        Ok(ref mode) if mode == "mtime" => false,
+        Ok(mode) => bail!(
+            "invalid XBUILD_SRC_FINGERPRINT `{}`; possible values are mtime and content",
+            mode
+        ),
+        Err(_) => false,
+    };
+
+    hash_tree(dir, content)
+}
+
+/// Hashes the files in `dir` by their contents if `content` is set, and by their sizes and
+/// modification times otherwise
+fn hash_tree(dir: &Path, content: bool) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let walk = WalkDir::new(dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|e| {
+            let name = e.file_name().to_string_lossy();
+            e.depth() == 0 || !(name.starts_with('.') || name == "target")
+        });
+    for e in walk {
+        let e = e.with_context(|| {
+            format!(
+                "intermittent IO error while iterating directory `{}`",
+                dir.display()
+            )
+        })?;
+        if !e.file_type().is_file() {
+            continue;
+        }
+
+        let relative_path = e.path().strip_prefix(dir).unwrap();
+        hasher.update(relative_path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        if content {
+            let contents = fs::read(e.path())
+                .with_context(|| format!("couldn't read {}", e.path().display()))?;
+            hasher.update(&contents);
+        } else {
+            let metadata = e.metadata().with_context(|| {
+                format!("Could not retrieve metadata of `{}`", e.path().display())
+            })?;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            hasher.update(metadata.len().to_le_bytes());
+            hasher.update(mtime.as_nanos().to_le_bytes());
+        }
+    }
+
+    let mode = if content { "content" } else { "mtime" };
+    Ok(format!("{}:{}", mode, hex(&hasher.finalize())))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Describes the differences between the `old` and `new` value of the field `path`
+fn diff(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    if old == new {
+        return;
+    }
+
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff(
+                    &path,
+                    old.get(key).unwrap_or(&Value::Null),
+                    new.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(old), Value::Array(new)) if old.iter().chain(new).all(Value::is_string) => {
+            let added = new
+                .iter()
+                .filter(|v| !old.contains(v))
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>();
+            let removed = old
+                .iter()
+                .filter(|v| !new.contains(v))
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>();
+
+            let mut parts = vec![];
+            if !added.is_empty() {
+                parts.push(format!("added {}", added.join(" ")));
+            }
+            if !removed.is_empty() {
+                parts.push(format!("removed {}", removed.join(" ")));
+            }
+            if parts.is_empty() {
+                parts.push("reordered".to_owned());
+            }
+            changes.push(format!("{} changed: {}", path, parts.join(", ")));
+        }
+        _ => {
+            let (old, new) = (old.to_string(), new.to_string());
+            if old.len() + new.len() > 80 {
+                changes.push(format!("{} changed", path));
+            } else {
+                changes.push(format!("{} changed: {} -> {}", path, old, new));
+            }
+        }
+    }
+}
+
+/// Explains why the sysroot in `dir` has to be rebuilt to match `fingerprint`
+pub fn explain(dir: &Path, fingerprint: &Fingerprint) -> Result<Vec<String>> {
+    if !dir.join(".hash").exists() {
+        return Ok(vec!["the sysroot hasn't been built yet".to_owned()]);
+    }
+
+    let changes = match Fingerprint::read(dir) {
+        Some(old) => fingerprint.changes(&old),
+        None => vec![],
+    };
+    if changes.is_empty() {
+        let hash = util::read(&dir.join(".hash")).context("couldn't read the old hash")?;
+        if hash.trim() != fingerprint.hash() {
+            return Ok(vec![
+                "the sysroot was built by a different version of cargo-xbuild".to_owned(),
+            ]);
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fingerprint() -> Fingerprint {
+        Fingerprint {
+            triple: "x86_64-unknown-none".to_owned(),
+            rustc: "rustc 1.60.0-nightly (1bd4fdc94 2022-01-12)".to_owned(),
+            rustc_commit: Some("1bd4fdc943513e1004f498bbf289279c9784fc6f".to_owned()),
+            rustflags: vec!["-Ccode-model=kernel".to_owned()],
+            target_spec: None,
+            profile: "dev".to_owned(),
+            profile_settings: None,
+            config: json!({ "crates": ["core", "alloc"], "memcpy": true }),
+            src: None,
+            lockfile: None,
+            patches: BTreeMap::new(),
+            dependencies: BTreeMap::new(),
+            git_commits: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn equal_fingerprints_have_no_changes() {
+        assert_eq!(fingerprint().hash(), fingerprint().hash());
+        assert!(fingerprint().changes(&fingerprint()).is_empty());
+    }
+
+    #[test]
+    fn changes_of_lists() {
+        let old = fingerprint();
+        let mut new = fingerprint();
+        new.rustflags = vec!["-Ctarget-feature=+soft-float".to_owned()];
+        assert_ne!(old.hash(), new.hash());
+        assert_eq!(
+            new.changes(&old),
+            ["rustflags changed: added -Ctarget-feature=+soft-float, removed -Ccode-model=kernel"]
+        );
+
+        new.rustflags = vec![];
+        assert_eq!(
+            new.changes(&old),
+            ["rustflags changed: removed -Ccode-model=kernel"]
+        );
+
+        new.config = json!({ "crates": ["alloc", "core"], "memcpy": true });
+        assert_eq!(new.changes(&old)[0], "config.crates changed: reordered");
+    }
+
+    #[test]
+    fn changes_of_values() {
+        let old = fingerprint();
+        let mut new = fingerprint();
+        new.profile = "release".to_owned();
+        new.config = json!({ "crates": ["core", "alloc"], "memcpy": false });
+        new.patches
+            .insert("fix.patch".to_owned(), "ab12".to_owned());
+        // long values are left out
+        new.rustc_commit = Some("0".repeat(40));
+        assert_eq!(
+            new.changes(&old),
+            [
+                "config.memcpy changed: true -> false",
+                "patches.fix.patch changed: null -> \"ab12\"",
+                "profile changed: \"dev\" -> \"release\"",
+                "rustc_commit changed",
+            ]
+        );
+    }
+
+    #[test]
+    fn explain_compares_with_the_written_fingerprint() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = td.path();
+        assert_eq!(
+            explain(dir, &fingerprint()).unwrap(),
+            ["the sysroot hasn't been built yet"]
+        );
+
+        fingerprint().write(dir).unwrap();
+        assert!(explain(dir, &fingerprint()).unwrap().is_empty());
+
+        let mut new = fingerprint();
+        new.triple = "x86_64-unknown-uefi".to_owned();
+        assert_eq!(
+            explain(dir, &new).unwrap(),
+            ["triple changed: \"x86_64-unknown-none\" -> \"x86_64-unknown-uefi\""]
+        );
+
+        // a fingerprint that can't be compared only has a different hash
+        util::write(&dir.join(FINGERPRINT), "{}").unwrap();
+        assert!(explain(dir, &fingerprint()).unwrap().is_empty());
+        util::write(&dir.join(".hash"), "0").unwrap();
+        assert_eq!(
+            explain(dir, &fingerprint()).unwrap(),
+            ["the sysroot was built by a different version of cargo-xbuild"]
+        );
+    }
+
+    #[test]
+    fn git_commits_are_kept_while_the_override_is_unchanged() {
+        let git = json!({ "git": "https://example.com/cb", "branch": "main" });
+        let mut old = fingerprint();
+        old.config = json!({ "dependencies": { "compiler_builtins": git } });
+        old.lock_git_commits(
+            vec![("compiler_builtins".to_owned(), "0123abc".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+
+        let mut new = fingerprint();
+        new.config = old.config.clone();
+        new.keep_git_commits(&old);
+        assert_eq!(new.git_commits(), old.git_commits());
+        assert_eq!(new.hash(), old.hash());
+
+        let mut new = fingerprint();
+        new.config = json!({
+            "dependencies": {
+                "compiler_builtins": { "git": "https://example.com/cb", "branch": "next" }
+            }
+        });
+        new.keep_git_commits(&old);
+        assert!(new.git_commits().is_empty());
+
+        let mut new = fingerprint();
+        new.keep_git_commits(&old);
+        assert!(new.git_commits().is_empty());
+    }
+
+    #[test]
+    fn tree_fingerprint_modes() {
+        let td = tempfile::tempdir().unwrap();
+        let file = td.path().join("lib.rs");
+        fs::write(&file, "fn a() {}").unwrap();
+        let content = hash_tree(td.path(), true).unwrap();
+        let mtime = hash_tree(td.path(), false).unwrap();
+        assert!(content.starts_with("content:"), "{}", content);
+        assert!(mtime.starts_with("mtime:"), "{}", mtime);
+
+        // Hidden files and `target` directories are skipped
+        fs::write(td.path().join(".swp"), "").unwrap();
+        fs::create_dir(td.path().join("target")).unwrap();
+        fs::write(td.path().join("target").join("out"), "").unwrap();
+        assert_eq!(hash_tree(td.path(), true).unwrap(), content);
+        assert_eq!(hash_tree(td.path(), false).unwrap(), mtime);
+
+        // Touching the file only changes the modification time
+        let touched = SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(touched)
+            .unwrap();
+        assert_eq!(hash_tree(td.path(), true).unwrap(), content);
+        let mtime_touched = hash_tree(td.path(), false).unwrap();
+        assert_ne!(mtime_touched, mtime);
+
+        // An edit that keeps the size and the modification time is only seen in the contents
+        fs::write(&file, "fn b() {}").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(touched)
+            .unwrap();
+        assert_ne!(hash_tree(td.path(), true).unwrap(), content);
+        assert_eq!(hash_tree(td.path(), false).unwrap(), mtime_touched);
+    }
+}
@@ -6,6 +6,8 @@ use std::path::{Display, Path, PathBuf};
 use std::{fs, io};
 
 use self::sys::*;
+use crate::message::{Message, Messages};
+use crate::Error;
 
 #[derive(PartialEq)]
 enum State {
@@ -47,14 +49,14 @@ impl FileLock {
 
 pub struct Filesystem {
     path: PathBuf,
-    quiet: bool,
+    messages: Messages,
 }
 
 impl Filesystem {
-    pub fn new(path: PathBuf, quiet: bool) -> Filesystem {
+    pub fn new(path: PathBuf, messages: &Messages) -> Filesystem {
         Filesystem {
-            path: path,
-            quiet: quiet,
+            path,
+            messages: messages.clone(),
         }
     }
 
@@ -62,10 +64,10 @@ impl Filesystem {
     where
         T: AsRef<Path>,
     {
-        Filesystem::new(self.path.join(other), self.quiet)
+        Filesystem::new(self.path.join(other), &self.messages)
     }
 
-    pub fn open_ro<P>(&self, path: P, msg: &str) -> io::Result<FileLock>
+    pub fn open_ro<P>(&self, path: P, msg: &str) -> Result<FileLock, Error>
     where
         P: AsRef<Path>,
     {
@@ -77,7 +79,7 @@ impl Filesystem {
         )
     }
 
-    pub fn open_rw<P>(&self, path: P, msg: &str) -> io::Result<FileLock>
+    pub fn open_rw<P>(&self, path: P, msg: &str) -> Result<FileLock, Error>
     where
         P: AsRef<Path>,
     {
@@ -95,13 +97,24 @@ impl Filesystem {
         opts: &OpenOptions,
         state: State,
         msg: &str,
-    ) -> io::Result<FileLock> {
+    ) -> Result<FileLock, Error> {
         let path = self.path.join(path);
 
-        let f = opts.open(&path).or_else(|e| {
+        self.lock(&path, opts, state, msg)
+            .map_err(|source| Error::LockFailed { path, source })
+    }
+
+    fn lock(
+        &self,
+        path: &Path,
+        opts: &OpenOptions,
+        state: State,
+        msg: &str,
+    ) -> io::Result<FileLock> {
+        let f = opts.open(path).or_else(|e| {
             if e.kind() == io::ErrorKind::NotFound && state == State::Exclusive {
                 create_dir_all(path.parent().unwrap())?;
-                opts.open(&path)
+                opts.open(path)
             } else {
                 Err(e)
             }
@@ -109,12 +122,16 @@ impl Filesystem {
 
         match state {
             State::Exclusive => {
-                acquire(msg, &path, self.quiet, &|| try_lock_exclusive(&f), &|| {
-                    lock_exclusive(&f)
-                })?;
+                acquire(
+                    msg,
+                    path,
+                    &self.messages,
+                    &|| try_lock_exclusive(&f),
+                    &|| lock_exclusive(&f),
+                )?;
             }
             State::Shared => {
-                acquire(msg, &path, self.quiet, &|| try_lock_shared(&f), &|| {
+                acquire(msg, path, &self.messages, &|| try_lock_shared(&f), &|| {
                     lock_shared(&f)
                 })?;
             }
@@ -122,13 +139,17 @@ impl Filesystem {
 
         Ok(FileLock {
             file: f,
-            path: path,
+            path: path.to_owned(),
         })
     }
 
-    pub fn display(&self) -> Display {
+    pub fn display(&self) -> Display<'_> {
         self.path.display()
     }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 }
 
 impl Drop for FileLock {
@@ -140,7 +161,7 @@ impl Drop for FileLock {
 fn acquire(
     msg: &str,
     path: &Path,
-    quiet: bool,
+    messages: &Messages,
     lock_try: &dyn Fn() -> io::Result<()>,
     lock_block: &dyn Fn() -> io::Result<()>,
 ) -> io::Result<()> {
@@ -187,9 +208,11 @@ fn acquire(
         }
     }
 
-    if !quiet {
-        eprintln!("{:>12} waiting for file lock on {}", "Blocking", msg)
-    }
+    messages.observer().lock_contended(path, msg);
+    messages.emit(Message::LockWaiting {
+        path,
+        description: msg,
+    });
 
     lock_block()
 }
@@ -221,7 +244,7 @@ mod sys {
     }
 
     pub(super) fn error_contended(err: &Error) -> bool {
-        err.raw_os_error().map_or(false, |x| x == libc::EWOULDBLOCK)
+        err.raw_os_error() == Some(libc::EWOULDBLOCK)
     }
 
     pub(super) fn error_unsupported(err: &Error) -> bool {
@@ -1,27 +1,42 @@
 #![cfg_attr(feature = "backtrace", feature(backtrace))]
 
-use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
+use std::sync::Arc;
 use std::{env, process};
 
 use anyhow::{anyhow, bail, Context, Result};
-use rustc_version::Channel;
-
-use self::rustc::Target;
-
+use rustc_version::{Channel, VersionMeta};
+
+use self::cache::Cache;
+use self::cargo::{Profile, Rustflags};
+use self::fingerprint::Fingerprint;
+use self::message::Messages;
+use self::rustc::{Src, Sysroot, Target};
+use self::xargo::Home;
+
+mod archive;
+mod builder;
+mod cache;
 mod cargo;
 mod cli;
 mod config;
+mod error;
 mod extensions;
+mod fingerprint;
 mod flock;
+mod message;
+mod observer;
 mod rustc;
 mod sysroot;
 mod util;
 mod xargo;
 
+pub use self::builder::{SysrootBuilder, SysrootInfo};
 pub use self::cli::{Args, Verbosity};
-pub use self::config::Config;
+pub use self::config::{Config, ConfigBuilder, Dependency};
+pub use self::error::Error;
+pub use self::observer::{BuildObserver, StderrObserver};
 
 // We use a different sysroot for Native compilation to avoid file locking
 //
@@ -36,18 +51,6 @@ pub enum CompilationMode {
 }
 
 impl CompilationMode {
-    fn hash<H>(&self, hasher: &mut H) -> Result<()>
-    where
-        H: Hasher,
-    {
-        match *self {
-            CompilationMode::Cross(ref target) => target.hash(hasher)?,
-            CompilationMode::Native(ref triple) => triple.hash(hasher),
-        }
-
-        Ok(())
-    }
-
     /// Returns the condensed target triple (removes any `.json` extension and path components).
     fn triple(&self) -> &str {
         match *self {
@@ -64,12 +67,20 @@ impl CompilationMode {
         }
     }
 
-    fn is_native(&self) -> bool {
+    /// Returns the value to pass to `--target` for rustc itself: the path of the target
+    /// specification file for custom targets, the triple otherwise.
+    fn target_spec(&self) -> &str {
         match *self {
-            CompilationMode::Native(_) => true,
-            _ => false,
+            CompilationMode::Cross(Target::Custom { ref json, .. }) => {
+                json.to_str().unwrap_or_else(|| self.orig_triple())
+            }
+            _ => self.orig_triple(),
         }
     }
+
+    fn is_native(&self) -> bool {
+        matches!(*self, CompilationMode::Native(_))
+    }
 }
 
 pub fn main_common(command_name: &str) {
@@ -82,7 +93,7 @@ pub fn main_common(command_name: &str) {
         Err(e) => {
             eprintln!("error: {}", e);
 
-            for e in e.chain().into_iter().skip(1) {
+            for e in e.chain().skip(1) {
                 eprintln!("caused by: {}", e);
             }
 
@@ -112,6 +123,36 @@ fn run(command_name: &str) -> Result<Option<ExitStatus>> {
     let (command, args) = cli::args(command_name)?;
     match command {
         Command::Build => Ok(Some(build(args, command_name, None)?)),
+        Command::Sysroot => {
+            let messages = Messages::new(args.json(), stderr_observer(&args));
+            messages.sysroot(&build_sysroot(args, None)?);
+            Ok(None)
+        }
+        Command::Export(archive) => {
+            export_sysroot(args, None, &archive)?;
+            Ok(None)
+        }
+        Command::Import(archive) => {
+            let messages = Messages::new(args.json(), stderr_observer(&args));
+            messages.sysroot(&import_sysroot(args, None, &archive)?);
+            Ok(None)
+        }
+        Command::Print(what) => {
+            print(args, what)?;
+            Ok(None)
+        }
+        Command::Clean { stale } => {
+            clean(args, None, stale)?;
+            Ok(None)
+        }
+        Command::Help if command_name == "clean" => {
+            print!(include_str!("help-clean.txt"));
+            Ok(None)
+        }
+        Command::Help if command_name == "sysroot" => {
+            print!(include_str!("help-sysroot.txt"));
+            Ok(None)
+        }
         Command::Help => {
             print!(include_str!("help.txt"), command_name = command_name);
             Ok(None)
@@ -131,14 +172,308 @@ fn run(command_name: &str) -> Result<Option<ExitStatus>> {
 /// If `crate_config` is provided it will override the values in the `Cargo.toml`.
 /// Otherwise the config specified in the `[package.metadata.cargo-xbuild]` section will be used.
 pub fn build(args: Args, command_name: &str, crate_config: Option<Config>) -> Result<ExitStatus> {
-    let verbose = args.verbose();
-    let quiet = args.quiet();
-    let meta = rustc::version().map_err(|e| anyhow!("getting rustc version failed: {}", e))?;
+    let observer = stderr_observer(&args);
+    build_with_observer(args, command_name, crate_config, observer)
+}
+
+/// Like [`build`], but reports the progress to `observer` instead of printing it to stderr
+pub fn build_with_observer(
+    args: Args,
+    command_name: &str,
+    crate_config: Option<Config>,
+    observer: Arc<dyn BuildObserver + Send + Sync>,
+) -> Result<ExitStatus> {
+    let default_profile = cli::default_profile(command_name);
+    let plan = Plan::from_args(&args, crate_config, default_profile, observer)?;
+    let targets = match plan.targets {
+        Ok(ref targets) => targets,
+        Err(_) => return cargo::run(&args, &plan.messages),
+    };
+
+    plan.update_sysroots()?;
+
+    // Targets with the same flags are built by a single cargo invocation
+    let mut builds: Vec<(Vec<&CompilationMode>, &Rustflags)> = vec![];
+    for (cmode, rustflags) in targets {
+        match builds.iter_mut().find(|(_, flags)| *flags == rustflags) {
+            Some((cmodes, _)) => cmodes.push(cmode),
+            None => builds.push((vec![cmode], rustflags)),
+        }
+    }
+
+    if let [(ref cmodes, rustflags)] = builds[..] {
+        return xargo::run(
+            args.all(),
+            cmodes,
+            rustflags,
+            &plan.home,
+            &plan.meta,
+            command_name,
+            &plan.messages,
+        );
+    }
+
+    let mut results = vec![];
+    for (cmodes, rustflags) in &builds {
+        let triples = cmodes.iter().map(|c| c.orig_triple()).collect::<Vec<_>>();
+        let status = xargo::run(
+            &args.all_for_targets(&triples),
+            cmodes,
+            rustflags,
+            &plan.home,
+            &plan.meta,
+            command_name,
+            &plan.messages,
+        )?;
+        results.push((triples, status));
+    }
+
+    let observer = plan.messages.observer();
+    for (triples, status) in &results {
+        if status.success() {
+            observer.info("Finished", &triples.join(", "));
+        } else {
+            observer.info("Failed", &format!("{} ({})", triples.join(", "), status));
+        }
+    }
+
+    let (_, status) = results
+        .iter()
+        .find(|(_, status)| !status.success())
+        .unwrap_or_else(|| results.last().unwrap());
+    Ok(*status)
+}
+
+/// Builds or refreshes the sysroot for all targets without running any cargo command
+/// afterwards.
+///
+/// Returns the path of the sysroot, which can be passed to `rustc` through `--sysroot`.
+///
+/// The `crate_config` argument works like for [`build`].
+pub fn build_sysroot(args: Args, crate_config: Option<Config>) -> Result<PathBuf> {
+    let plan = Plan::from_args(&args, crate_config, "dev", stderr_observer(&args))?;
+    plan.sysroot_targets()?;
+
+    plan.update_sysroots()?;
+
+    Ok(plan.home.path().to_owned())
+}
+
+/// Builds or refreshes the sysroot for all targets and writes it to a `.tar.zst` archive
+///
+/// The archive contains a manifest of the inputs of the sysroot hash, which is checked by
+/// [`import_sysroot`].
+///
+/// The `crate_config` argument works like for [`build`].
+pub fn export_sysroot(args: Args, crate_config: Option<Config>, archive: &Path) -> Result<()> {
+    let plan = Plan::from_args(&args, crate_config, "dev", stderr_observer(&args))?;
+    plan.sysroot_targets()?;
+
+    plan.update_sysroots()?;
+    archive::export(archive, &plan.home, plan.fingerprints()?)?;
+
+    plan.messages
+        .observer()
+        .info("Exported", &format!("sysroot to {}", archive.display()));
+
+    Ok(())
+}
+
+/// Unpacks the sysroot for all targets from an archive created by [`export_sysroot`]
+///
+/// The archive is rejected if the sysroot in it was built with a different hash than the one
+/// that would be built here. No Rust source is needed.
+///
+/// Returns the path of the sysroot. The `crate_config` argument works like for [`build`].
+pub fn import_sysroot(args: Args, crate_config: Option<Config>, archive: &Path) -> Result<PathBuf> {
+    let plan = Plan::from_args(&args, crate_config, "dev", stderr_observer(&args))?;
+    plan.sysroot_targets()?;
+
+    archive::import(archive, &plan.home, plan.fingerprints()?)?;
+    plan.update_host()?;
+
+    plan.messages
+        .observer()
+        .info("Imported", &format!("sysroot from {}", archive.display()));
+
+    Ok(plan.home.path().to_owned())
+}
+
+/// Removes the sysroots of the targets passed through `--target`, or of all targets if there
+/// are none
+///
+/// If `stale` is set, only the sysroots that were built by a different `rustc` are removed.
+/// Returns the number of bytes freed.
+///
+/// The `crate_config` argument works like for [`build`].
+pub fn clean(args: Args, crate_config: Option<Config>, stale: bool) -> Result<u64> {
     let cd = CurrentDirectory::get()?;
-    let config = cargo::config()?;
+    let (root, crate_config) = workspace(
+        args.manifest_path(),
+        args.packages(),
+        args.workspace(),
+        args.exclude(),
+        crate_config,
+        &cd,
+    )?;
+    let messages = Messages::new(args.json(), stderr_observer(&args));
+    let homes = match args.profile() {
+        Some(profile) => vec![(
+            profile.to_owned(),
+            xargo::home(&root, &crate_config, profile, &messages)?,
+        )],
+        None => xargo::homes(&root, &crate_config, &messages)?,
+    };
+
+    let meta = if stale {
+        Some(rustc::version().map_err(|e| anyhow!("getting rustc version failed: {}", e))?)
+    } else {
+        None
+    };
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for (profile, home) in &homes {
+        let triples = if args.targets().is_empty() {
+            home.triples()?
+        } else {
+            args.targets()
+                .iter()
+                .map(|triple| match triple.strip_suffix(".json") {
+                    Some(json) => Path::new(json)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| triple.clone()),
+                    None => triple.clone(),
+                })
+                .collect()
+        };
+
+        for triple in &triples {
+            if let Some(size) = sysroot::remove(triple, home, meta.as_ref())? {
+                messages.observer().info(
+                    "Removed",
+                    &format!("{} ({}, {})", triple, profile, util::human_size(size)),
+                );
+                removed += 1;
+                freed += size;
+            }
+        }
+    }
+
+    messages.observer().info(
+        "Removed",
+        &format!(
+            "{} sysroot{}, {} total",
+            removed,
+            if removed == 1 { "" } else { "s" },
+            util::human_size(freed)
+        ),
+    );
+
+    Ok(freed)
+}
+
+/// The observer that prints the progress like the command line tools
+fn stderr_observer(args: &Args) -> Arc<dyn BuildObserver + Send + Sync> {
+    Arc::new(StderrObserver::new(args.quiet(), args.verbose()))
+}
+
+/// Prints the resolved sysroot path, rustflags, environment or targets without building
+/// anything
+fn print(args: Args, what: cli::Print) -> Result<()> {
+    use cli::Print;
+
+    let plan = Plan::from_args(&args, None, "dev", stderr_observer(&args))?;
+    let targets = plan.sysroot_targets()?;
+
+    let single = || match targets[..] {
+        [(ref cmode, ref rustflags)] => Ok((cmode, rustflags)),
+        _ => Err(anyhow!(
+            "the rustflags can only be printed for a single target"
+        )),
+    };
+
+    match what {
+        Print::Sysroot => println!("{}", plan.home.display()),
+        Print::Target => {
+            for (cmode, _) in targets {
+                println!("{}", cmode.orig_triple());
+            }
+        }
+        Print::Rustflags => {
+            let (_, rustflags) = single()?;
+            let encoded = rustflags.for_xargo(&plan.home);
+            let flags = encoded.split('\x1f').collect::<Vec<_>>();
+            // A space-separated list can't represent flags with whitespace, e.g. a sysroot
+            // path with spaces
+            if let Some(flag) = flags.iter().find(|f| f.contains(char::is_whitespace)) {
+                bail!(
+                    "the rustflag `{}` contains whitespace, so the rustflags can't be printed \
+                     space-separated; use `--print env` or `--print env-json` to get \
+                     CARGO_ENCODED_RUSTFLAGS instead",
+                    flag
+                );
+            }
+            println!("{}", flags.join(" "));
+        }
+        Print::Env => {
+            let (cmode, rustflags) = single()?;
+            for (key, value) in cargo_env(cmode, rustflags, &plan.home) {
+                println!("export {}='{}'", key, value.replace('\'', "'\\''"));
+            }
+        }
+        Print::EnvJson => {
+            let (cmode, rustflags) = single()?;
+            let map = cargo_env(cmode, rustflags, &plan.home)
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), serde_json::Value::String(value)))
+                .collect::<serde_json::Map<_, _>>();
+            println!("{}", serde_json::Value::Object(map));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the environment variables that make cargo build for `cmode` with the sysroot in
+/// `home`
+fn cargo_env(
+    cmode: &CompilationMode,
+    rustflags: &Rustflags,
+    home: &Home,
+) -> Vec<(&'static str, String)> {
+    let encoded = rustflags.for_xargo(home);
+    let mut env = vec![
+        ("CARGO_BUILD_TARGET", cmode.orig_triple().to_owned()),
+        ("CARGO_ENCODED_RUSTFLAGS", encoded.clone()),
+        ("CARGO_ENCODED_RUSTDOCFLAGS", encoded),
+    ];
+    if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+        if let Some(dir) = json.parent() {
+            env.push(("RUST_TARGET_PATH", dir.display().to_string()));
+        }
+    }
+
+    env
+}
 
+/// Returns the workspace root and the cargo-xbuild config of the selected packages
+///
+/// The packages are the ones passed through `-p` or, if `all` is set, all workspace members
+/// except the `exclude`d ones.
+/// Without any, the package of `manifest_path` or of the nearest manifest is selected. If
+/// `crate_config` is provided, it is used instead of the config in the manifests.
+fn workspace(
+    manifest_path: Option<&Path>,
+    packages: &[String],
+    all: bool,
+    exclude: &[String],
+    crate_config: Option<Config>,
+    cd: &CurrentDirectory,
+) -> Result<(PathBuf, Config)> {
     let mut cmd = cargo_metadata::MetadataCommand::new();
-    if let Some(manifest_path) = args.manifest_path() {
+    if let Some(manifest_path) = manifest_path {
         cmd.manifest_path(manifest_path);
     }
 
@@ -149,92 +484,331 @@ pub fn build(args: Args, command_name: &str, crate_config: Option<Config>) -> Re
 
     // Fall back to manifest if config not explicitly specified
     let crate_config = crate_config.map(Ok).unwrap_or_else(|| {
-        Config::from_metadata(&metadata, args.quiet()).map_err(|e| {
-            anyhow!(
-                "reading package.metadata.cargo-xbuild section failed: {}",
-                e
-            )
-        })
+        // Cargo builds the package of the nearest manifest unless told otherwise
+        let manifest_path = match manifest_path {
+            Some(manifest_path) => manifest_path.to_owned(),
+            None => util::search(cd.path(), "Cargo.toml")
+                .unwrap_or(root)
+                .join("Cargo.toml"),
+        };
+        let packages = if all {
+            metadata
+                .packages
+                .iter()
+                .filter(|p| metadata.workspace_members.contains(&p.id))
+                .map(|p| p.name.clone())
+                .collect()
+        } else {
+            packages.to_vec()
+        };
+        Config::from_workspace(&metadata, &packages, exclude, &manifest_path)
+            .context("reading the cargo-xbuild config failed")
     })?;
 
-    // We can't build sysroot with stable or beta due to unstable features
-    let sysroot = rustc::sysroot(verbose)?;
-    let src = match meta.channel {
-        Channel::Dev => rustc::Src::from_env().ok_or(anyhow!(
-            "The XARGO_RUST_SRC env variable must be set and point to the \
-             Rust source directory when working with the 'dev' channel",
-        ))?,
-        Channel::Nightly => {
-            if let Some(src) = rustc::Src::from_env() {
-                src
-            } else {
-                sysroot.src()?
-            }
-        }
-        Channel::Stable | Channel::Beta => {
-            bail!(
-                "The sysroot can't be built for the {:?} channel. \
-                 Switch to nightly.",
-                meta.channel
-            );
-        }
+    Ok((root.to_owned(), crate_config))
+}
+
+/// Returns the Rust source that the sysroot is built from
+///
+/// This is `custom` if given, else the source in `XARGO_RUST_SRC` or, on the nightly channel,
+/// the `rust-src` component. It is `None` if none of them exists.
+fn src(meta: &VersionMeta, sysroot: &Sysroot, custom: Option<&Path>) -> Result<Option<Src>> {
+    let from_env = || {
+        custom
+            .map(|path| Src::new(path.to_owned()))
+            .or_else(Src::from_env)
     };
+    match meta.channel {
+        // The 'dev' channel has no `rust-src` component
+        Channel::Dev => Ok(from_env()),
+        Channel::Nightly => Ok(from_env().or_else(|| sysroot.src())),
+        // We can't build sysroot with stable or beta due to unstable features
+        Channel::Stable | Channel::Beta => bail!(Error::UnsupportedChannel {
+            channel: format!("{:?}", meta.channel),
+        }),
+    }
+}
+
+/// Returns the compilation mode for `triple`, or `None` if it is an unknown target
+fn cmode(
+    triple: &str,
+    meta: &VersionMeta,
+    cd: &CurrentDirectory,
+    verbose: bool,
+) -> Result<Option<CompilationMode>> {
+    if triple == meta.host {
+        Ok(Some(CompilationMode::Native(meta.host.clone())))
+    } else {
+        Ok(Target::new(triple, cd, verbose)?.map(CompilationMode::Cross))
+    }
+}
+
+/// Everything that has been resolved from the arguments and the configuration that is needed
+/// to build the sysroots and to run cargo with them
+struct Plan {
+    meta: VersionMeta,
+    root: PathBuf,
+    crate_config: Config,
+    /// The Rust source, which is only needed if a sysroot has to be built
+    src: Option<Src>,
+    sysroot: Sysroot,
+    home: Home,
+    /// The machine-wide sysroot cache, if enabled
+    cache: Option<Cache>,
+    /// The profile that the sysroots are built with
+    profile: Profile,
+    /// The targets to build for, together with their rustflags
+    ///
+    /// This is the triple of the only target if it is unknown, in which case plain cargo is
+    /// run.
+    targets: Result<Vec<(CompilationMode, Rustflags)>, String>,
+    /// Whether cargo must not access the network, through `--offline`, `--frozen` or the
+    /// `net.offline` configuration key
+    offline: bool,
+    /// Whether the lockfile of the Rust source must not change, through `--locked` or
+    /// `--frozen`
+    locked: bool,
+    messages: Messages,
+    verbose: bool,
+}
+
+/// The inputs that a [`Plan`] is resolved from, given either on the command line or through
+/// the [`SysrootBuilder`]
+struct Inputs<'a> {
+    manifest_path: Option<&'a Path>,
+    packages: &'a [String],
+    all: bool,
+    exclude: &'a [String],
+    /// The cargo-xbuild config, instead of the one in the manifests
+    crate_config: Option<Config>,
+    /// The `--config` arguments
+    cargo_config: &'a [String],
+    /// The targets, or none for the `build.target` configuration key or the host
+    targets: &'a [String],
+    /// The Rust source, instead of `XARGO_RUST_SRC` or the `rust-src` component
+    rust_src: Option<&'a Path>,
+    /// The rustflags, instead of the ones in the environment and the Cargo configuration
+    rustflags: Option<&'a [String]>,
+    profile: &'a str,
+    offline: bool,
+    locked: bool,
+    json: bool,
+    verbose: bool,
+    observer: Arc<dyn BuildObserver + Send + Sync>,
+}
+
+impl Plan {
+    /// Resolves the plan from the command line, with the `default_profile` of the cargo
+    /// subcommand
+    fn from_args(
+        args: &Args,
+        crate_config: Option<Config>,
+        default_profile: &str,
+        observer: Arc<dyn BuildObserver + Send + Sync>,
+    ) -> Result<Plan> {
+        Plan::new(Inputs {
+            manifest_path: args.manifest_path(),
+            packages: args.packages(),
+            all: args.workspace(),
+            exclude: args.exclude(),
+            crate_config,
+            cargo_config: args.cargo_config(),
+            targets: args.targets(),
+            rust_src: None,
+            rustflags: None,
+            profile: args.profile().unwrap_or(default_profile),
+            offline: args.offline(),
+            locked: args.locked(),
+            json: args.json(),
+            verbose: args.verbose(),
+            observer,
+        })
+    }
+
+    fn new(inputs: Inputs) -> Result<Plan> {
+        let verbose = inputs.verbose;
+        let messages = Messages::new(inputs.json, inputs.observer);
+        let meta = rustc::version().map_err(|e| anyhow!("getting rustc version failed: {}", e))?;
+        let cd = CurrentDirectory::get()?;
+        let config = cargo::config(inputs.cargo_config)?;
+
+        let (root, crate_config) = workspace(
+            inputs.manifest_path,
+            inputs.packages,
+            inputs.all,
+            inputs.exclude,
+            inputs.crate_config,
+            &cd,
+        )?;
+
+        let sysroot = rustc::sysroot(verbose)?;
+        let src = src(&meta, &sysroot, inputs.rust_src)?;
 
-    let cmode = if let Some(triple) = args.target() {
-        if triple == meta.host {
-            Some(CompilationMode::Native(meta.host.clone()))
+        let triples = if !inputs.targets.is_empty() {
+            inputs.targets.to_vec()
+        } else if let Some(ref config) = config {
+            config.targets()?
         } else {
-            Target::new(triple, &cd, verbose)?.map(CompilationMode::Cross)
+            vec![]
+        };
+
+        let cmodes = if triples.is_empty() {
+            vec![Some(CompilationMode::Native(meta.host.clone()))]
+        } else {
+            triples
+                .iter()
+                .map(|triple| cmode(triple, &meta, &cd, verbose))
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        if cmodes
+            .iter()
+            .any(|cmode| matches!(cmode, Some(CompilationMode::Native(_))))
+        {
+            messages.warning(
+                "building for the host system, which is likely an error and will cause build \
+                 scripts of dependencies to break. To build for the target system either pass \
+                 a `--target` argument or set the `build.target` configuration key in a \
+                 `.cargo/config.toml` file.",
+            );
         }
-    } else {
-        if let Some(ref config) = config {
-            if let Some(triple) = config.target()? {
-                Target::new(&triple, &cd, verbose)?.map(CompilationMode::Cross)
-            } else {
-                Some(CompilationMode::Native(meta.host.clone()))
+
+        // Plain cargo can only be run for a single unknown target, since the known ones
+        // would be built without their sysroot
+        if cmodes.len() > 1 {
+            if let Some(i) = cmodes.iter().position(Option::is_none) {
+                bail!(Error::TargetNotFound {
+                    target: triples[i].clone(),
+                });
+            }
+        }
+
+        // The sysroot of the host target is built into the directory that the other targets
+        // copy the host artifacts of `rustc` into, for build scripts and procedural macros
+        if cmodes.len() > 1 {
+            if let Some(i) = cmodes
+                .iter()
+                .position(|cmode| matches!(cmode, Some(CompilationMode::Native(_))))
+            {
+                bail!(
+                    "the host target `{}` can't be built together with other targets, since \
+                     they share the host part of the sysroot; build it in a separate cargo \
+                     invocation",
+                    triples[i]
+                );
             }
-        } else {
-            Some(CompilationMode::Native(meta.host.clone()))
         }
-    };
 
-    if let Some(CompilationMode::Native(_)) = cmode {
-        eprintln!(
-            "WARNING: You're currently building for the host system. This is likely an \
-            error and will cause build scripts of dependencies to break.\n\n\
+        let custom_rustflags = inputs.rustflags;
+        let targets = match cmodes.into_iter().collect::<Option<Vec<_>>>() {
+            Some(cmodes) => Ok(cmodes
+                .into_iter()
+                .map(|cmode| {
+                    let rustflags = match custom_rustflags {
+                        Some(flags) => Rustflags::new(flags.to_vec()),
+                        None => cargo::rustflags(config.as_ref(), &cmode, verbose)?,
+                    };
+                    Ok((cmode, rustflags))
+                })
+                .collect::<Result<Vec<_>>>()?),
+            None => Err(triples[0].clone()),
+        };
+
+        let offline = inputs.offline || config.as_ref().is_some_and(|c| c.offline());
+        let ctoml = cargo::toml(&root)?;
+        let profile = cargo::profile(&ctoml, config.as_ref(), inputs.profile)?;
+        let home = xargo::home(&root, &crate_config, profile.name(), &messages)?;
+        let cache = Cache::from_env(&messages)?;
+
+        Ok(Plan {
+            meta,
+            root,
+            crate_config,
+            src,
+            sysroot,
+            home,
+            cache,
+            profile,
+            targets,
+            offline,
+            locked: inputs.locked,
+            messages,
+            verbose,
+        })
+    }
 
-            To build for the target system either pass a `--target` argument or \
-            set the build.target configuration key in a `.cargo/config` file.\n",
-        );
+    /// Returns the targets, or an error if no sysroot can be built for one of them
+    fn sysroot_targets(&self) -> Result<&[(CompilationMode, Rustflags)]> {
+        match self.targets {
+            Ok(ref targets) => Ok(targets),
+            Err(ref target) => bail!(Error::TargetNotFound {
+                target: target.clone(),
+            }),
+        }
     }
 
-    if let Some(cmode) = cmode {
-        let home = xargo::home(root, &crate_config, quiet)?;
-        let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
+    /// Builds or refreshes the sysroot of every target
+    fn update_sysroots(&self) -> Result<Vec<SysrootInfo>> {
+        self.targets
+            .iter()
+            .flatten()
+            .map(|(cmode, rustflags)| {
+                sysroot::update(
+                    cmode,
+                    &self.home,
+                    &self.root,
+                    &self.crate_config,
+                    &self.profile,
+                    rustflags,
+                    &self.meta,
+                    self.src.as_ref(),
+                    &self.sysroot,
+                    self.cache.as_ref(),
+                    self.offline,
+                    self.locked,
+                    &self.messages,
+                    self.verbose,
+                )
+            })
+            .collect()
+    }
 
-        sysroot::update(
-            &cmode,
-            &home,
-            &root,
-            &crate_config,
-            &rustflags,
-            &meta,
-            &src,
-            &sysroot,
-            verbose,
-        )?;
-        return xargo::run(
-            &args,
-            &cmode,
-            rustflags,
-            &home,
-            &meta,
-            command_name,
-            verbose,
-        );
+    /// Copies the host artifacts into the sysroot if it is used for cross compilation
+    fn update_host(&self) -> Result<()> {
+        if self
+            .targets
+            .iter()
+            .flatten()
+            .all(|(cmode, _)| cmode.is_native())
+        {
+            return Ok(());
+        }
+
+        sysroot::update_host(&self.home, &self.meta, &self.sysroot, &self.messages)
     }
 
-    cargo::run(&args, verbose)
+    /// Computes the fingerprint of every target's sysroot
+    fn fingerprints(&self) -> Result<Vec<Fingerprint>> {
+        self.targets
+            .iter()
+            .flatten()
+            .map(|(cmode, rustflags)| {
+                let mut fingerprint = Fingerprint::new(
+                    cmode,
+                    rustflags,
+                    &self.profile,
+                    &self.meta,
+                    &self.crate_config,
+                    self.src.as_ref(),
+                    &self.root,
+                )?;
+                if let Some(old) = Fingerprint::read(&self.home.triple_dir(cmode.triple())) {
+                    fingerprint.keep_git_commits(&old);
+                }
+                Ok(fingerprint)
+            })
+            .collect()
+    }
 }
 
 pub struct CurrentDirectory {
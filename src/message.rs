@@ -0,0 +1,98 @@
+//! JSON messages about the sysroot build for `--message-format=json`
+//!
+//! The messages are printed to stdout as one JSON object per line, like the messages of Cargo
+//! itself, so that tools can read a single stream. Each message has a `reason` field that
+//! starts with `sysroot-` and doesn't clash with the reasons used by Cargo.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::observer::BuildObserver;
+
+#[derive(Serialize)]
+#[serde(tag = "reason")]
+pub enum Message<'a> {
+    /// The fingerprint of a sysroot has been computed
+    #[serde(rename = "sysroot-fingerprint")]
+    Fingerprint {
+        target: &'a str,
+        hash: &'a str,
+        /// Whether the sysroot is up to date
+        fresh: bool,
+    },
+    /// The machine-wide sysroot cache has been checked for a sysroot
+    #[serde(rename = "sysroot-cache")]
+    Cache {
+        target: &'a str,
+        hash: &'a str,
+        hit: bool,
+    },
+    /// The sysroot of a target is being built, with the requested crates and all sysroot
+    /// crates they depend on
+    #[serde(rename = "sysroot-build-started")]
+    BuildStarted {
+        target: &'a str,
+        crates: &'a [&'a str],
+    },
+    #[serde(rename = "sysroot-build-finished")]
+    BuildFinished { target: &'a str, success: bool },
+    /// Artifacts have been copied into the sysroot of a target
+    #[serde(rename = "sysroot-artifacts-copied")]
+    ArtifactsCopied { target: &'a str, path: &'a Path },
+    /// A lock that is held by another process is waited for
+    #[serde(rename = "sysroot-lock-waiting")]
+    LockWaiting {
+        path: &'a Path,
+        description: &'a str,
+    },
+    #[serde(rename = "sysroot-warning")]
+    Warning { message: &'a str },
+    /// The sysroot is ready at `path`, printed by `cargo xsysroot` instead of the bare path
+    #[serde(rename = "sysroot-path")]
+    Sysroot { path: &'a Path },
+}
+
+/// Prints messages if the JSON message format is selected and reports progress to the
+/// [`BuildObserver`]
+#[derive(Clone)]
+pub struct Messages {
+    json: bool,
+    observer: Arc<dyn BuildObserver + Send + Sync>,
+}
+
+impl Messages {
+    pub fn new(json: bool, observer: Arc<dyn BuildObserver + Send + Sync>) -> Messages {
+        Messages { json, observer }
+    }
+
+    pub fn observer(&self) -> &dyn BuildObserver {
+        &*self.observer
+    }
+
+    pub fn emit(&self, message: Message) {
+        if self.json {
+            println!(
+                "{}",
+                serde_json::to_string(&message).expect("messages can always be serialized")
+            );
+        }
+    }
+
+    /// Prints the path of the sysroot to stdout, as a message if the JSON message format is
+    /// selected, so that it doesn't break the stream of messages
+    pub fn sysroot(&self, path: &Path) {
+        if self.json {
+            self.emit(Message::Sysroot { path });
+        } else {
+            println!("{}", path.display());
+        }
+    }
+
+    /// Reports a warning to the observer and emits it as a message
+    pub fn warning(&self, message: &str) {
+        self.observer.warning(message);
+        self.emit(Message::Warning { message });
+    }
+}
@@ -0,0 +1,90 @@
+//! Hooks that report the progress of the sysroot build
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+
+/// Receives progress events of the sysroot build, e.g. to show a spinner while the sysroot is
+/// rebuilt
+///
+/// All methods do nothing by default. The command line tools use [`StderrObserver`]. The
+/// observer is shared through an `Arc`, so it must be `Send` and `Sync`.
+pub trait BuildObserver {
+    /// The sysroot for `target` is out of date and is about to be rebuilt or restored from the
+    /// cache, for the given `reasons`
+    fn sysroot_rebuild_started(&self, _target: &str, _reasons: &[String]) {}
+
+    /// The rebuild of the sysroot for `target` has finished, with an error if it failed
+    fn sysroot_rebuild_finished(&self, _target: &str, _result: &Result<()>) {}
+
+    /// The sysroot at `path` is up to date for `target` and is reused
+    fn sysroot_up_to_date(&self, _target: &str, _path: &Path) {}
+
+    /// The lock file at `path`, which protects `description`, is held by another process and
+    /// is waited for
+    fn lock_contended(&self, _path: &Path, _description: &str) {}
+
+    /// `cmd` is about to be run, e.g. the cargo command that builds the sysroot or the cargo
+    /// subcommand that is run with it
+    fn command_spawned(&self, _cmd: &Command) {}
+
+    /// A warning about the build, e.g. that it is for the host system
+    fn warning(&self, _message: &str) {}
+
+    /// A step has been done, e.g. a sysroot has been restored from the cache or removed.
+    /// `status` is a single word like `Restored` and `message` describes the step.
+    fn info(&self, _status: &str, _message: &str) {}
+}
+
+/// Prints the progress to stderr like `cargo xbuild` does
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrObserver {
+    quiet: bool,
+    verbose: bool,
+}
+
+impl StderrObserver {
+    /// `quiet` hides waits for locks, warnings and the steps that have been done, `verbose` shows why sysroots are rebuilt and which
+    /// commands are run
+    pub fn new(quiet: bool, verbose: bool) -> Self {
+        StderrObserver { quiet, verbose }
+    }
+}
+
+impl BuildObserver for StderrObserver {
+    fn sysroot_rebuild_started(&self, target: &str, reasons: &[String]) {
+        if self.verbose {
+            for reason in reasons {
+                eprintln!("{:>12} sysroot for {}: {}", "Dirty", target, reason);
+            }
+        }
+    }
+
+    fn lock_contended(&self, _path: &Path, description: &str) {
+        if !self.quiet {
+            eprintln!(
+                "{:>12} waiting for file lock on {}",
+                "Blocking", description
+            )
+        }
+    }
+
+    fn command_spawned(&self, cmd: &Command) {
+        if self.verbose {
+            eprintln!("+ {:?}", cmd);
+        }
+    }
+
+    fn warning(&self, message: &str) {
+        if !self.quiet {
+            eprintln!("warning: {}", message);
+        }
+    }
+
+    fn info(&self, status: &str, message: &str) {
+        if !self.quiet {
+            eprintln!("{:>12} {}", status, message);
+        }
+    }
+}
@@ -1,16 +1,16 @@
 use std::env;
-use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub use rustc_version::version_meta as version;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, Result};
+use cargo_platform::Cfg;
 use serde_json::Value;
 
 use crate::extensions::CommandExt;
-use crate::CurrentDirectory;
 use crate::{rustc, util};
+use crate::{CurrentDirectory, Error};
 
 fn command() -> Command {
     env::var_os("RUSTC")
@@ -21,15 +21,28 @@ fn command() -> Command {
 /// `rustc --print target-list`
 pub fn targets(verbose: bool) -> Result<Vec<String>> {
     command()
-        .args(&["--print", "target-list"])
+        .args(["--print", "target-list"])
         .run_and_get_stdout(verbose)
         .map(|t| t.lines().map(|l| l.to_owned()).collect())
 }
 
+/// `rustc --print cfg --target $TARGET`
+pub fn cfg(target: &str, verbose: bool) -> Result<Vec<Cfg>> {
+    command()
+        .args(["--print", "cfg", "--target", target])
+        .run_and_get_stdout(verbose)?
+        .lines()
+        .map(|l| {
+            l.parse()
+                .map_err(|e| anyhow!("couldn't parse `{}` printed by rustc: {}", l, e))
+        })
+        .collect()
+}
+
 /// `rustc --print sysroot`
 pub fn sysroot(verbose: bool) -> Result<Sysroot> {
     command()
-        .args(&["--print", "sysroot"])
+        .args(["--print", "sysroot"])
         .run_and_get_stdout(verbose)
         .map(|l| Sysroot {
             path: PathBuf::from(l.trim()),
@@ -38,18 +51,50 @@ pub fn sysroot(verbose: bool) -> Result<Sysroot> {
 /// Path to Rust source
 pub struct Src {
     path: PathBuf,
+    custom: bool,
 }
 
 impl Src {
+    /// Returns a custom source at `path`
+    pub fn new(path: PathBuf) -> Self {
+        Src { path, custom: true }
+    }
+
     pub fn from_env() -> Option<Self> {
-        env::var_os("XARGO_RUST_SRC").map(|s| Src {
-            path: PathBuf::from(s),
-        })
+        env::var_os("XARGO_RUST_SRC").map(|s| Src::new(PathBuf::from(s)))
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// Whether the source was given explicitly or through `XARGO_RUST_SRC` instead of being
+    /// the `rust-src` component of the toolchain
+    ///
+    /// The `rust-src` component is identified by the `rustc` commit hash, whereas a custom
+    /// source may be edited at any time.
+    pub fn is_custom(&self) -> bool {
+        self.custom
+    }
+
+    /// Returns a source at `path`, which is a modified copy of this one
+    pub fn copy_at(&self, path: PathBuf) -> Src {
+        Src {
+            path,
+            custom: self.custom,
+        }
+    }
+
+    /// The lockfile that pins the dependencies of the sysroot crates
+    pub fn lockfile(&self) -> PathBuf {
+        // Newer sources make the `library` directory its own workspace with its own lockfile,
+        // older ones only have the lockfile of the whole Rust repository in the parent directory
+        if self.path.join("Cargo.lock").is_file() {
+            self.path.join("Cargo.lock")
+        } else {
+            self.path.join("..").join("Cargo.lock")
+        }
+    }
 }
 
 /// Path to `rustc`'s sysroot
@@ -63,20 +108,18 @@ impl Sysroot {
     }
 
     /// Returns the path to Rust source, `$SRC`, where `$SRC/std/Cargo.toml`
-    /// exists
-    pub fn src(&self) -> Result<Src> {
+    /// exists, if the `rust-src` component is installed
+    pub fn src(&self) -> Option<Src> {
         let src = self.path().join("lib").join("rustlib").join("src");
 
         if src.join("rust/library/std/Cargo.toml").is_file() {
-            return Ok(Src {
+            return Some(Src {
                 path: src.join("rust/library"),
+                custom: false,
             });
         }
 
-        Err(anyhow!(
-            "`rust-src` component not found. Run `rustup component add \
-             rust-src`."
-        ))
+        None
     }
 }
 
@@ -97,7 +140,7 @@ impl Target {
         let triple = triple.to_owned();
 
         if rustc::targets(verbose)?.iter().any(|t| t == &triple) {
-            Ok(Some(Target::Builtin { triple: triple }))
+            Ok(Some(Target::Builtin { triple }))
         } else {
             let json = PathBuf::from(&triple);
             if json.exists() {
@@ -119,7 +162,7 @@ impl Target {
 
             if json.exists() {
                 return Ok(Some(Target::Custom {
-                    json: json,
+                    json,
                     triple: triple.clone(),
                     orig_triple: triple,
                 }));
@@ -131,7 +174,7 @@ impl Target {
 
                     if json.exists() {
                         return Ok(Some(Target::Custom {
-                            json: json,
+                            json,
                             triple: triple.clone(),
                             orig_triple: triple,
                         }));
@@ -161,19 +204,18 @@ impl Target {
         }
     }
 
-    pub fn hash<H>(&self, hasher: &mut H) -> Result<()>
-    where
-        H: Hasher,
-    {
-        if let Target::Custom { ref json, .. } = *self {
-            // Here we roundtrip to/from JSON to get the same hash when some
-            // fields of the JSON file has been shuffled around
-            serde_json::from_str::<Value>(&util::read(json)?)
-                .with_context(|| format!("{} is not valid JSON", json.display()))?
-                .to_string()
-                .hash(hasher);
+    /// Returns the contents of the target specification file of custom targets
+    pub fn spec(&self) -> Result<Option<Value>> {
+        match *self {
+            // Parsing the JSON makes the fingerprint independent of the order of its fields
+            Target::Custom { ref json, .. } => match serde_json::from_str(&util::read(json)?) {
+                Ok(spec) => Ok(Some(spec)),
+                Err(source) => bail!(Error::InvalidTargetJson {
+                    path: json.clone(),
+                    source,
+                }),
+            },
+            Target::Builtin { .. } => Ok(None),
         }
-
-        Ok(())
     }
 }
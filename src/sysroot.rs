@@ -1,66 +1,253 @@
-use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::hash::{Hash, Hasher};
-use std::path::Path;
-use std::process::Command;
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
-use anyhow::{Context, Result};
-use rustc_version::VersionMeta;
-use tempfile::Builder;
+use anyhow::{anyhow, bail, Context, Result};
+use rustc_version::{Channel, VersionMeta};
+use tempfile::{Builder, TempDir};
 use toml::{value::Table, Value};
 
-use crate::cargo::{self, Rustflags};
+use crate::cache::Cache;
+use crate::cargo::{self, Profile, Rustflags};
 use crate::config::Config;
 use crate::extensions::CommandExt;
+use crate::fingerprint::{self, Fingerprint};
+use crate::message::{Message, Messages};
 use crate::rustc::{Src, Sysroot, Target};
 use crate::util;
 use crate::xargo::Home;
-use crate::CompilationMode;
+use crate::{CompilationMode, Error, SysrootInfo};
 
-#[cfg(feature = "dev")]
-fn profile() -> &'static str {
-    "debug"
-}
-
-#[cfg(not(feature = "dev"))]
-fn profile() -> &'static str {
-    "release"
-}
+/// The file next to `.hash` that records the version of the `rustc` that built the sysroot
+const TOOLCHAIN: &str = ".toolchain";
 
+#[allow(clippy::too_many_arguments)]
 fn build(
     cmode: &CompilationMode,
     ctoml: &cargo::Toml,
+    profile: &Profile,
     home: &Home,
     config: &Config,
-    src: &Src,
-    hash: u64,
+    rustflags: &Rustflags,
+    src: Option<&Src>,
+    root: &Path,
+    fingerprint: &mut Fingerprint,
+    meta: &VersionMeta,
+    cache: Option<&Cache>,
+    offline: bool,
+    locked: bool,
+    messages: &Messages,
     verbose: bool,
 ) -> Result<()> {
     let rustlib = home.lock_rw(cmode.triple())?;
     rustlib
         .remove_siblings()
         .with_context(|| format!("couldn't clear {}", rustlib.path().display()))?;
-    let dst = rustlib.parent().join("lib");
-    util::mkdir(&dst)?;
 
-    build_liballoc(cmode, &ctoml, src, &dst, config, verbose)?;
+    let hash = fingerprint.hash();
+    let cached = match cache {
+        Some(cache) => {
+            let hit = cache
+                .fetch(cmode.triple(), &hash, rustlib.parent())
+                .unwrap_or_else(|e| {
+                    messages.warning(&format!(
+                        "couldn't restore the sysroot from the cache: {:#}",
+                        e
+                    ));
+                    false
+                });
+            messages.emit(Message::Cache {
+                target: cmode.triple(),
+                hash: &hash,
+                hit,
+            });
+            hit
+        }
+        None => false,
+    };
+
+    if !cached {
+        // A failed restore may have left files behind
+        rustlib
+            .remove_siblings()
+            .with_context(|| format!("couldn't clear {}", rustlib.path().display()))?;
+        let src = src.ok_or(Error::MissingRustSrc {
+            dev: meta.channel == Channel::Dev,
+        })?;
+        let patches = config.patch_files(root)?;
+        let patched;
+        let src = if patches.is_empty() {
+            src
+        } else {
+            patched = patch(src, root, &patches, messages, verbose)?;
+            &patched.1
+        };
+        let dst = rustlib.parent().join("lib");
+        util::mkdir(&dst)?;
+
+        let crates = resolve_crates(&config.crates)?
+            .iter()
+            .map(|c| c.name)
+            .collect::<Vec<_>>();
+        messages.emit(Message::BuildStarted {
+            target: cmode.triple(),
+            crates: &crates,
+        });
+        let result = build_libs(
+            cmode,
+            ctoml,
+            profile,
+            src,
+            root,
+            &dst,
+            config,
+            fingerprint.git_commits(),
+            rustflags,
+            offline,
+            locked,
+            messages,
+            verbose,
+        );
+        messages.emit(Message::BuildFinished {
+            target: cmode.triple(),
+            success: result.is_ok(),
+        });
+        fingerprint.lock_git_commits(result?);
+
+        if let Some(cache) = cache {
+            if let Err(e) = cache.store(&fingerprint.hash(), rustlib.parent()) {
+                messages.warning(&format!("couldn't store the sysroot in the cache: {:#}", e));
+            }
+        }
+    }
+    messages.emit(Message::ArtifactsCopied {
+        target: cmode.triple(),
+        path: rustlib.parent(),
+    });
 
+    util::write(
+        &rustlib.parent().join(TOOLCHAIN),
+        &meta.short_version_string,
+    )?;
     // Create hash file
-    util::write(&rustlib.parent().join(".hash"), &hash.to_string())?;
+    fingerprint.write(rustlib.parent())?;
 
     Ok(())
 }
 
+/// Applies the `patches` to a temporary copy of the Rust source
+///
+/// The copy of the source is placed in a `library` directory, so the patches apply with
+/// paths relative to the root of the Rust repository, like `git format-patch` creates them.
+/// Only the `library` directory is copied, so it is an error if a patch changes files
+/// elsewhere, e.g. in a crates.io dependency like `compiler_builtins`.
+fn patch(
+    src: &Src,
+    root: &Path,
+    patches: &[PathBuf],
+    messages: &Messages,
+    verbose: bool,
+) -> Result<(TempDir, Src)> {
+    let td = Builder::new()
+        .prefix("cargo-xbuild-src")
+        .tempdir()
+        .with_context(|| "couldn't create a temporary directory")?;
+
+    for patch in patches {
+        let numstat = git_apply(td.path())
+            .args(["--numstat", "-z"])
+            .arg(root.join(patch))
+            .run_and_get_stdout(verbose)
+            .with_context(|| format!("reading the patch {} failed", patch.display()))?;
+        if let Some(path) = patched_paths(&numstat)
+            .into_iter()
+            .find(|path| !Path::new(path).starts_with("library"))
+        {
+            bail!(
+                "the patch {} changes `{}`, which is outside of the `library` directory of \
+                 the Rust source; only the sysroot crates in `library` can be patched. To \
+                 patch a crates.io dependency like `compiler_builtins`, override it with a \
+                 patched checkout in the `dependencies` table instead.",
+                patch.display(),
+                path
+            );
+        }
+    }
+
+    let library = td.path().join("library");
+    util::cp_r(src.path(), &library)?;
+    let lockfile = src.lockfile();
+    if !src.path().join("Cargo.lock").is_file() && lockfile.is_file() {
+        fs::copy(&lockfile, td.path().join("Cargo.lock"))
+            .with_context(|| format!("failed to copy Cargo.lock from `{}`", lockfile.display()))?;
+    }
+
+    for patch in patches {
+        git_apply(td.path())
+            .arg(root.join(patch))
+            .run(messages)
+            .with_context(|| format!("applying the patch {} failed", patch.display()))?;
+    }
+
+    let src = src.copy_at(library);
+    Ok((td, src))
+}
+
+/// Returns a `git apply` command that applies patches in `dir`
+///
+/// The same command is used to list the paths of a patch, so that they are resolved the same
+/// way as when the patch is applied.
+fn git_apply(dir: &Path) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.arg("apply")
+        .current_dir(dir)
+        // Don't let git mistake an enclosing repository for the one to patch
+        .env("GIT_CEILING_DIRECTORIES", dir.parent().unwrap_or(dir));
+    cmd
+}
+
+/// Returns the paths that are changed by a patch, given the output of
+/// `git apply --numstat -z`
+///
+/// Each file is listed as `<added>\t<deleted>\t<path>\0`, or, if it is renamed or copied, as
+/// `<added>\t<deleted>\t\0<old path>\0<new path>\0`.
+fn patched_paths(numstat: &str) -> Vec<&str> {
+    let mut paths = vec![];
+
+    let mut fields = numstat.split('\0');
+    while let Some(field) = fields.next() {
+        match field.splitn(3, '\t').nth(2) {
+            Some("") => paths.extend(fields.by_ref().take(2)),
+            Some(path) => paths.push(path),
+            None => {}
+        }
+    }
+
+    paths
+}
+
+#[allow(clippy::too_many_arguments)]
 fn build_crate(
     crate_name: &str,
     lockfile: &Path,
+    versions: &[(&str, &str)],
     mut stoml: String,
     cmode: &CompilationMode,
     ctoml: &cargo::Toml,
+    profile: &Profile,
+    rustflags: &Rustflags,
     dst: &Path,
+    vendor: Option<&Path>,
+    offline: bool,
+    locked: bool,
+    overrides: &[&str],
+    messages: &Messages,
     verbose: bool,
-) -> Result<()> {
+) -> Result<String> {
     let td = Builder::new()
         .prefix("cargo-xbuild")
         .tempdir()
@@ -68,7 +255,9 @@ fn build_crate(
     let td_path;
     let td = if env::var_os("XBUILD_KEEP_TEMP").is_some() {
         td_path = td.into_path();
-        println!("XBUILD_KEEP_TEMP: files at {:?}", td_path);
+        messages
+            .observer()
+            .info("Keeping", &format!("files at {}", td_path.display()));
         &td_path
     } else {
         td.path()
@@ -80,9 +269,7 @@ fn build_crate(
         stoml.insert_str(0, &features.to_string())
     }
 
-    if let Some(profile) = ctoml.profile() {
-        stoml.push_str(&profile.to_string())
-    }
+    stoml.push_str(&profile.to_string());
 
     util::write(&td.join("Cargo.toml"), &stoml)?;
     let td_lockfile = &td.join("Cargo.lock");
@@ -93,7 +280,7 @@ fn build_crate(
             td_lockfile.display()
         )
     })?;
-    let mut perms = fs::metadata(&td_lockfile)
+    let mut perms = fs::metadata(td_lockfile)
         .with_context(|| {
             format!(
                 "failed to retrieve permissions for `{}`",
@@ -101,19 +288,65 @@ fn build_crate(
             )
         })?
         .permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
     perms.set_readonly(false);
-    fs::set_permissions(&td_lockfile, perms).with_context(|| {
+    fs::set_permissions(td_lockfile, perms).with_context(|| {
         format!(
             "failed to set writable permission for `{}`",
             td_lockfile.display()
         )
     })?;
     util::mkdir(&td.join("src"))?;
-    util::write(&td.join("src/lib.rs"), "")?;
+    util::write(
+        &td.join("src/lib.rs"),
+        "#![feature(no_core)]\n#![no_core]\n",
+    )?;
+
+    // Arguments for every cargo invocation in the temporary workspace
+    let mut args = vec![
+        "--manifest-path".into(),
+        td.join("Cargo.toml").into_os_string(),
+    ];
+    if let Some(vendor) = vendor {
+        // Cargo only discovers configuration files from the current directory, which stays
+        // the user's one, so the source replacement is passed explicitly
+        let config = td.join(".cargo").join("config.toml");
+        util::mkdir(config.parent().unwrap())?;
+        util::write(&config, &vendor_config(vendor))?;
+        args.push("--config".into());
+        args.push(config.into_os_string());
+    }
+    if offline {
+        args.push("--offline".into());
+    }
 
     let cargo = std::env::var("CARGO").unwrap_or("cargo".to_string());
+    for (name, version) in versions {
+        Command::new(&cargo)
+            .args(["update", "-p", name, "--precise", version])
+            .args(&args)
+            .run(messages)
+            .with_context(|| format!("couldn't lock `{}` to version {}", name, version))?;
+    }
+
+    if locked {
+        lock_root(&cargo, &args, lockfile, td_lockfile, overrides, messages)?;
+        args.push("--locked".into());
+    }
+
+    if offline && vendor.is_none() {
+        fetch_offline(&cargo, &args, cmode, messages)?;
+    }
+
     let mut cmd = Command::new(cargo);
-    cmd.env("RUSTFLAGS", "-Cembed-bitcode=yes");
+    // The sysroot crates need to be compiled with the user's flags (e.g. target features) to
+    // be ABI compatible with the crates that link against them. `force-unstable-if-unmarked`
+    // needs to reach every sysroot crate, not only the root one.
+    let mut flags = rustflags.for_sysroot();
+    flags.push("-Cembed-bitcode=yes".to_owned());
+    flags.push("-Zforce-unstable-if-unmarked".to_owned());
+    cmd.env_remove("RUSTFLAGS");
+    cmd.env("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f"));
     cmd.env("CARGO_TARGET_DIR", &target_dir);
     cmd.env("__CARGO_DEFAULT_LIB_METADATA", "XARGO");
 
@@ -125,178 +358,620 @@ fn build_crate(
     // file stored in the current directory we'll set `RUST_TARGET_PATH`  to the current
     // directory.
     if env::var_os("RUST_TARGET_PATH").is_none() {
-        if let CompilationMode::Cross(ref target) = *cmode {
-            if let Target::Custom { ref json, .. } = *target {
-                cmd.env("RUST_TARGET_PATH", json.parent().unwrap());
-            }
+        if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+            cmd.env("RUST_TARGET_PATH", json.parent().unwrap());
         }
     }
 
-    cmd.arg("rustc");
+    cmd.arg("build");
     cmd.arg("-p").arg(crate_name);
-
-    match () {
-        #[cfg(feature = "dev")]
-        () => {}
-        #[cfg(not(feature = "dev"))]
-        () => {
-            cmd.arg("--release");
-        }
-    }
-    cmd.arg("--manifest-path");
-    cmd.arg(td.join("Cargo.toml"));
-    cmd.args(&["--target", cmode.orig_triple()]);
+    cmd.args(["--profile", profile.name()]);
+    cmd.args(&args);
+    cmd.args(["--target", cmode.orig_triple()]);
 
     if verbose {
         cmd.arg("-v");
     }
 
-    cmd.arg("--");
-    cmd.arg("-Z");
-    cmd.arg("force-unstable-if-unmarked");
+    run_build(&mut cmd, messages)?;
+
+    let deps = target_dir
+        .join(cmode.triple())
+        .join(profile.dir_name())
+        .join("deps");
 
-    cmd.run(verbose)?;
+    // The root crate is an empty placeholder, so it must not end up in the sysroot
+    for entry in fs::read_dir(&deps).with_context(|| format!("couldn't read {}", deps.display()))? {
+        let path = entry
+            .with_context(|| format!("couldn't read {}", deps.display()))?
+            .path();
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        if file_name.starts_with(&format!("lib{}-", crate_name))
+            || file_name.starts_with(&format!("{}-", crate_name))
+        {
+            fs::remove_file(&path)
+                .with_context(|| format!("couldn't remove {}", path.display()))?;
+        }
+    }
 
     // Copy artifacts to Xargo sysroot
-    util::cp_r(
-        &target_dir.join(cmode.triple()).join(profile()).join("deps"),
-        dst,
-    )?;
+    util::cp_r(&deps, dst)?;
+
+    util::read(td_lockfile)
+}
+
+/// Runs the `cargo build` of the sysroot and reports which crate failed to compile, if any
+///
+/// The diagnostics are read from the JSON messages of Cargo and printed to stderr as rendered
+/// by `rustc`, so the output looks the same as with the human-readable message format.
+fn run_build(cmd: &mut Command, messages: &Messages) -> Result<()> {
+    let format = if io::stderr().is_terminal() {
+        "json-diagnostic-rendered-ansi"
+    } else {
+        "json"
+    };
+    cmd.arg(format!("--message-format={}", format));
+    cmd.stdout(Stdio::piped());
+
+    messages.observer().command_spawned(cmd);
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("couldn't execute `{:?}`", cmd))?;
+
+    let mut failed = None;
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    for line in stdout.lines() {
+        let line = line.with_context(|| format!("couldn't read the output of `{:?}`", cmd))?;
+        let message = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(message) => message,
+            Err(_) => {
+                eprintln!("{}", line);
+                continue;
+            }
+        };
+        if message["reason"] != "compiler-message" {
+            continue;
+        }
+
+        let diagnostic = &message["message"];
+        if let Some(rendered) = diagnostic["rendered"].as_str() {
+            eprint!("{}", rendered);
+        }
+        // The target of a build script is named `build-script-build`, not after its crate
+        let build_script = message["target"]["kind"]
+            .as_array()
+            .is_some_and(|kinds| kinds.iter().any(|kind| kind == "custom-build"));
+        if failed.is_none() && diagnostic["level"] == "error" && !build_script {
+            failed = message["target"]["name"].as_str().map(str::to_owned);
+        }
+    }
+
+    let status = child
+        .wait()
+        .with_context(|| format!("couldn't execute `{:?}`", cmd))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::SysrootBuildFailed {
+            krate: failed,
+            status,
+        }
+        .into())
+    }
+}
+
+/// Returns a Cargo configuration that replaces crates.io with the vendored sources in `vendor`
+fn vendor_config(vendor: &Path) -> String {
+    let mut crates_io = Table::new();
+    crates_io.insert(
+        "replace-with".to_owned(),
+        Value::String("xbuild-vendor".to_owned()),
+    );
+    let mut vendored = Table::new();
+    vendored.insert(
+        "directory".to_owned(),
+        Value::String(vendor.display().to_string()),
+    );
+    let mut source = Table::new();
+    source.insert("crates-io".to_owned(), Value::Table(crates_io));
+    source.insert("xbuild-vendor".to_owned(), Value::Table(vendored));
+    let mut config = Table::new();
+    config.insert("source".to_owned(), Value::Table(source));
+    Value::Table(config).to_string()
+}
+
+/// Adds the root package of the temporary workspace to its lockfile `td_lockfile`, which is
+/// a copy of the `lockfile` of the Rust source, and checks that nothing else had to change
+///
+/// The Rust source doesn't know the root package, so Cargo's `--locked` can only be passed
+/// afterwards. The dependencies in `overrides` are changed on purpose, so they may differ.
+fn lock_root(
+    cargo: &str,
+    args: &[OsString],
+    lockfile: &Path,
+    td_lockfile: &Path,
+    overrides: &[&str],
+    messages: &Messages,
+) -> Result<()> {
+    let old = util::read(td_lockfile)?;
+    // `cargo metadata` resolves the dependencies like a build and writes the lockfile
+    Command::new(cargo)
+        .args(["metadata", "--format-version", "1"])
+        .args(args)
+        .stdout(Stdio::null())
+        .run(messages)
+        .context("resolving the dependencies of the sysroot failed")?;
+    let new = util::read(td_lockfile)?;
+
+    let added = added_packages(&old, &new)?
+        .into_iter()
+        .filter(|(name, _)| name != "sysroot" && !overrides.contains(&&**name))
+        .map(|(name, version)| format!("{} v{}", name, version))
+        .collect::<Vec<_>>();
+    if !added.is_empty() {
+        bail!(
+            "the dependencies of the sysroot need to be updated, which --locked and --frozen \
+             forbid; {} isn't locked by {}",
+            added.join(", "),
+            lockfile.display()
+        );
+    }
 
     Ok(())
 }
 
-fn build_liballoc(
+/// Returns the name and version of the packages in the lockfile `new` that aren't in the
+/// lockfile `old` with the same version and source
+fn added_packages(old: &str, new: &str) -> Result<Vec<(String, String)>> {
+    fn packages(lockfile: &str) -> Result<Vec<(String, String, Option<String>)>> {
+        let lockfile: Value = lockfile.parse().context("couldn't parse Cargo.lock")?;
+        let packages = match lockfile.get("package").and_then(Value::as_array) {
+            Some(packages) => packages,
+            None => return Ok(vec![]),
+        };
+        Ok(packages
+            .iter()
+            .map(|package| {
+                let field = |key| package.get(key).and_then(Value::as_str).map(String::from);
+                (
+                    field("name").unwrap_or_default(),
+                    field("version").unwrap_or_default(),
+                    field("source"),
+                )
+            })
+            .collect())
+    }
+
+    let old = packages(old)?;
+    Ok(packages(new)?
+        .into_iter()
+        .filter(|package| !old.contains(package))
+        .map(|(name, version, _)| (name, version))
+        .collect())
+}
+
+/// Checks that the registry dependencies of the sysroot have been downloaded before
+///
+/// Without this check, Cargo fails in the middle of resolving or downloading with a message
+/// that doesn't tell how to fix the problem.
+fn fetch_offline(
+    cargo: &str,
+    args: &[OsString],
+    cmode: &CompilationMode,
+    messages: &Messages,
+) -> Result<()> {
+    Command::new(cargo)
+        .arg("fetch")
+        .args(args)
+        .args(["--target", cmode.orig_triple()])
+        .run(messages)
+        .with_context(|| {
+            format!(
+                "the registry dependencies of the sysroot for `{}` are not in the local \
+                 registry cache, so the sysroot can't be built offline; build it once with \
+                 network access or set the `vendor` key to a directory created by \
+                 `cargo vendor`",
+                cmode.triple()
+            )
+        })
+}
+
+/// A crate that can be built as part of the sysroot
+struct SysrootCrate {
+    name: &'static str,
+    /// Other sysroot crates that this crate depends on
+    deps: &'static [&'static str],
+    /// `rustc-std-workspace-*` crates that the registry dependencies of this crate
+    /// need to be patched to
+    shims: &'static [&'static str],
+}
+
+/// All crates that can be put into the sysroot, in dependency order
+const SYSROOT_CRATES: &[SysrootCrate] = &[
+    SysrootCrate {
+        name: "core",
+        deps: &[],
+        shims: &[],
+    },
+    SysrootCrate {
+        name: "compiler_builtins",
+        deps: &["core"],
+        shims: &["rustc-std-workspace-core"],
+    },
+    SysrootCrate {
+        name: "alloc",
+        deps: &["core", "compiler_builtins"],
+        shims: &[],
+    },
+    SysrootCrate {
+        name: "panic_abort",
+        deps: &["core", "compiler_builtins", "alloc"],
+        shims: &[],
+    },
+    SysrootCrate {
+        name: "panic_unwind",
+        deps: &["core", "compiler_builtins", "alloc"],
+        shims: &[],
+    },
+    SysrootCrate {
+        name: "std",
+        deps: &["core", "compiler_builtins", "alloc", "panic_abort"],
+        shims: &["rustc-std-workspace-core", "rustc-std-workspace-alloc"],
+    },
+    SysrootCrate {
+        name: "proc_macro",
+        deps: &["core", "std"],
+        shims: &[],
+    },
+    SysrootCrate {
+        name: "test",
+        deps: &["core", "std", "panic_abort", "panic_unwind"],
+        shims: &["rustc-std-workspace-std"],
+    },
+];
+
+/// Returns the requested sysroot crates together with all sysroot crates they depend on,
+/// in dependency order
+fn resolve_crates(requested: &[String]) -> Result<Vec<&'static SysrootCrate>> {
+    let mut pending: Vec<&str> = requested.iter().map(|c| &**c).collect();
+    let mut selected = Vec::new();
+
+    while let Some(name) = pending.pop() {
+        if selected.contains(&name) {
+            continue;
+        }
+        let krate = SYSROOT_CRATES
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "`{}` can't be built as part of the sysroot; supported crates are: {}",
+                    name,
+                    SYSROOT_CRATES
+                        .iter()
+                        .map(|c| c.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+        selected.push(krate.name);
+        pending.extend(krate.deps);
+    }
+
+    Ok(SYSROOT_CRATES
+        .iter()
+        .filter(|c| selected.contains(&c.name))
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_libs(
     cmode: &CompilationMode,
     ctoml: &cargo::Toml,
+    profile: &Profile,
     src: &Src,
+    root: &Path,
     dst: &Path,
     config: &Config,
+    git_commits: &BTreeMap<String, String>,
+    rustflags: &Rustflags,
+    offline: bool,
+    locked: bool,
+    messages: &Messages,
     verbose: bool,
-) -> Result<()> {
-    const TOML: &'static str = r#"
+) -> Result<BTreeMap<String, String>> {
+    const TOML: &str = r#"
 [package]
 authors = ["The Rust Project Developers"]
-name = "alloc"
+name = "sysroot"
 version = "0.0.0"
 edition = "2021"
-
-[dependencies.compiler_builtins]
-version = "0.1.0"
 "#;
 
-    let mut stoml = TOML.to_owned();
-
-    if config.memcpy {
-        stoml.push_str("features = ['mem', 'core']\n");
-    } else {
-        stoml.push_str("features = ['core']\n");
+    let crates = resolve_crates(&config.crates)?;
+    if let Some(name) = config
+        .features
+        .keys()
+        .find(|name| !crates.iter().any(|c| c.name == *name))
+    {
+        bail!(
+            "features are configured for `{}`, but it is not part of the sysroot",
+            name
+        );
     }
 
-    stoml.push_str("[dependencies.core]\n");
-    stoml.push_str(&format!("path = '{}'\n", src.path().join("core").display()));
+    let mut deps = Table::new();
+    let mut shims = Table::new();
+    for krate in &crates {
+        let mut dep = Table::new();
+        let mut features = vec![];
+        match krate.name {
+            "compiler_builtins" => {
+                dep.insert("version".to_owned(), Value::String("0.1.0".to_owned()));
+                if config.memcpy {
+                    features.push("mem");
+                }
+                features.push("core");
+            }
+            name => {
+                let path = src.path().join(name).display().to_string();
+                dep.insert("path".to_owned(), Value::String(path));
+                match name {
+                    "core" if config.panic_immediate_abort => {
+                        features.push("panic_immediate_abort");
+                    }
+                    "std" => {
+                        if config.memcpy {
+                            features.push("compiler-builtins-mem");
+                        }
+                        if crates.iter().any(|c| c.name == "panic_unwind") {
+                            features.push("panic-unwind");
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(extra) = config.features.get(krate.name) {
+            for feature in extra {
+                if !features.contains(&&**feature) {
+                    features.push(feature);
+                }
+            }
+        }
+        if !features.is_empty() {
+            let features = features
+                .into_iter()
+                .map(|f| Value::String(f.to_owned()))
+                .collect();
+            dep.insert("features".to_owned(), Value::Array(features));
+        }
+        deps.insert(krate.name.to_owned(), Value::Table(dep));
 
-    if config.panic_immediate_abort {
-        stoml.push_str("features = ['panic_immediate_abort']\n");
+        for shim in krate.shims {
+            let mut patch = Table::new();
+            let path = src.path().join(shim).display().to_string();
+            patch.insert("path".to_owned(), Value::String(path));
+            shims.insert((*shim).to_owned(), Value::Table(patch));
+        }
     }
 
-    stoml.push_str("[patch.crates-io.rustc-std-workspace-core]\n");
-    stoml.push_str(&format!(
-        "path = '{}'\n",
-        src.path().join("rustc-std-workspace-core").display()
-    ));
+    // Git and path overrides become `[patch]` entries, which Cargo picks up when resolving
+    // the lockfile. Versions are selected in the lockfile itself.
+    let mut versions = vec![];
+    for (name, dependency) in &config.dependencies {
+        if let Some(ref version) = dependency.version {
+            versions.push((&**name, &**version));
+            continue;
+        }
+
+        let mut patch = Table::new();
+        if let Some(ref path) = dependency.path {
+            let path = root.join(path).display().to_string();
+            patch.insert("path".to_owned(), Value::String(path));
+        }
+        // A `git` override without a `rev` stays at the commit it was locked to before
+        let (branch, tag, rev) = match git_commits.get(name) {
+            Some(commit) => (None, None, Some(commit)),
+            None => (
+                dependency.branch.as_ref(),
+                dependency.tag.as_ref(),
+                dependency.rev.as_ref(),
+            ),
+        };
+        let git = [
+            ("git", dependency.git.as_ref()),
+            ("branch", branch),
+            ("tag", tag),
+            ("rev", rev),
+        ];
+        for (key, value) in git.iter() {
+            if let Some(value) = value {
+                patch.insert((*key).to_owned(), Value::String((*value).clone()));
+            }
+        }
+        shims.insert(name.clone(), Value::Table(patch));
+    }
 
-    let path = src.path().join("alloc/src/lib.rs").display().to_string();
+    let mut patch = Table::new();
+    patch.insert("crates-io".to_owned(), Value::Table(shims));
     let mut map = Table::new();
-    let mut lib = Table::new();
-    lib.insert("name".to_owned(), Value::String("alloc".to_owned()));
-    lib.insert("path".to_owned(), Value::String(path));
-    map.insert("lib".to_owned(), Value::Table(lib));
+    map.insert("dependencies".to_owned(), Value::Table(deps));
+    map.insert("patch".to_owned(), Value::Table(patch));
+
+    let mut stoml = TOML.to_owned();
     stoml.push_str(&Value::Table(map).to_string());
 
-    let lockfile = src.path().join("..").join("Cargo.lock");
+    let lockfile = src.lockfile();
+    let vendor = match config.vendor {
+        Some(ref vendor) => {
+            let vendor = root.join(vendor);
+            if !vendor.is_dir() {
+                bail!(
+                    "the vendor directory {} doesn't exist; create it with \
+                     `cargo vendor --manifest-path {} {}`",
+                    vendor.display(),
+                    src.path().join("Cargo.toml").display(),
+                    vendor.display()
+                );
+            }
+            Some(vendor)
+        }
+        None => None,
+    };
+
+    let overrides = config.dependencies.keys().map(|k| &**k).collect::<Vec<_>>();
+    let lockfile = build_crate(
+        "sysroot",
+        &lockfile,
+        &versions,
+        stoml,
+        cmode,
+        ctoml,
+        profile,
+        rustflags,
+        dst,
+        vendor.as_deref(),
+        offline,
+        locked,
+        &overrides,
+        messages,
+        verbose,
+    )?;
+
+    Ok(config
+        .dependencies
+        .iter()
+        .filter(|(_, dependency)| dependency.git.is_some() && dependency.rev.is_none())
+        .filter_map(|(name, _)| Some((name.clone(), locked_git_commit(&lockfile, name)?)))
+        .collect())
+}
 
-    build_crate("alloc", &lockfile, stoml, cmode, ctoml, dst, verbose)
+/// Returns the commit that the git dependency `name` is locked to in `lockfile`, the
+/// contents of a `Cargo.lock`
+///
+/// The `source` of a locked git dependency is like
+/// `git+https://github.com/rust-lang/compiler-builtins?branch=master#<commit>`.
+fn locked_git_commit(lockfile: &str, name: &str) -> Option<String> {
+    let lockfile: Value = lockfile.parse().ok()?;
+    lockfile
+        .get("package")?
+        .as_array()?
+        .iter()
+        .filter(|package| package.get("name").and_then(Value::as_str) == Some(name))
+        .filter_map(|package| package.get("source")?.as_str())
+        .filter(|source| source.starts_with("git+"))
+        .find_map(|source| Some(source.rsplit_once('#')?.1.to_owned()))
 }
 
-fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
+fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<String>> {
     // FIXME this should be `lock_ro`
     let lock = home.lock_rw(cmode.triple())?;
     let hfile = lock.parent().join(".hash");
 
     if hfile.exists() {
-        Ok(util::read(&hfile)?.parse().ok())
+        Ok(Some(util::read(&hfile)?.trim().to_owned()))
     } else {
         Ok(None)
     }
 }
 
-/// Computes the hash of the would-be target sysroot
-///
-/// This information is used to compute the hash
-///
-/// - RUSTFLAGS / build.rustflags / target.*.rustflags
-/// - The target specification file, is any
-/// - `[profile.release]` in `Cargo.toml`
-/// - `rustc` commit hash
-fn hash(
-    cmode: &CompilationMode,
-    rustflags: &Rustflags,
-    ctoml: &cargo::Toml,
-    meta: &VersionMeta,
-    config: &Config,
-) -> Result<u64> {
-    let mut hasher = DefaultHasher::new();
-
-    rustflags.hash(&mut hasher);
-
-    cmode.hash(&mut hasher)?;
-
-    if let Some(profile) = ctoml.profile() {
-        profile.hash(&mut hasher);
-    }
-
-    if let Some(ref hash) = meta.commit_hash {
-        hash.hash(&mut hasher);
-    }
-
-    config.hash(&mut hasher);
-
-    Ok(hasher.finish())
-}
-
+/// Builds the sysroot of `cmode` unless it is up to date
+#[allow(clippy::too_many_arguments)]
 pub fn update(
     cmode: &CompilationMode,
     home: &Home,
     root: &Path,
     config: &Config,
+    profile: &Profile,
     rustflags: &Rustflags,
     meta: &VersionMeta,
-    src: &Src,
+    src: Option<&Src>,
     sysroot: &Sysroot,
+    cache: Option<&Cache>,
+    offline: bool,
+    locked: bool,
+    messages: &Messages,
     verbose: bool,
-) -> Result<()> {
+) -> Result<SysrootInfo> {
     let ctoml = cargo::toml(root)?;
 
-    let hash = hash(cmode, rustflags, &ctoml, meta, config)?;
+    let dir = home.triple_dir(cmode.triple());
+    let mut fingerprint = Fingerprint::new(cmode, rustflags, profile, meta, config, src, root)?;
+    if let Some(old) = Fingerprint::read(&dir) {
+        fingerprint.keep_git_commits(&old);
+    }
+
+    let hash = fingerprint.hash();
+    let fresh = old_hash(cmode, home)?.as_ref() == Some(&hash);
+    messages.emit(Message::Fingerprint {
+        target: cmode.triple(),
+        hash: &hash,
+        fresh,
+    });
+
+    if fresh {
+        messages
+            .observer()
+            .sysroot_up_to_date(cmode.triple(), home.path());
+    } else {
+        let reasons = fingerprint::explain(&dir, &fingerprint)?;
+        messages
+            .observer()
+            .sysroot_rebuild_started(cmode.triple(), &reasons);
 
-    if old_hash(cmode, home)? != Some(hash) {
-        build(cmode, &ctoml, home, config, src, hash, verbose)?;
+        let result = build(
+            cmode,
+            &ctoml,
+            profile,
+            home,
+            config,
+            rustflags,
+            src,
+            root,
+            &mut fingerprint,
+            meta,
+            cache,
+            offline,
+            locked,
+            messages,
+            verbose,
+        );
+        messages
+            .observer()
+            .sysroot_rebuild_finished(cmode.triple(), &result);
+        result?;
     }
 
     // copy host artifacts into the sysroot, if necessary
-    if cmode.is_native() {
-        return Ok(());
+    if !cmode.is_native() {
+        update_host(home, meta, sysroot, messages)?;
     }
 
+    Ok(SysrootInfo {
+        path: home.path().to_owned(),
+        // A rebuild may have locked `git` overrides, which changes the hash
+        hash: fingerprint.hash(),
+        rebuilt: !fresh,
+    })
+}
+
+/// Copies the host artifacts of `rustc`'s sysroot into the sysroot, if necessary
+///
+/// Cross compilation needs them for build scripts and procedural macros.
+pub fn update_host(
+    home: &Home,
+    meta: &VersionMeta,
+    sysroot: &Sysroot,
+    messages: &Messages,
+) -> Result<()> {
     let lock = home.lock_rw(&meta.host)?;
     let hfile = lock.parent().join(".hash");
 
-    let hash = meta.commit_hash.as_ref().map(|s| &**s).unwrap_or("");
-    if hfile.exists() {
-        if util::read(&hfile)? == hash {
-            return Ok(());
-        }
+    let hash = meta.commit_hash.as_deref().unwrap_or("");
+    if hfile.exists() && util::read(&hfile)? == hash {
+        return Ok(());
     }
 
     lock.remove_siblings()
@@ -313,7 +988,10 @@ pub fn update(
         &dst,
     ) {
         Ok(()) => {}
-        Err(e) => eprintln!("Unable to copy the directory 'lib' from sysroot: {}", e),
+        Err(e) => messages.warning(&format!(
+            "unable to copy the directory 'lib' from sysroot: {}",
+            e
+        )),
     };
 
     let bin_dst = lock.parent().join("bin");
@@ -328,10 +1006,186 @@ pub fn update(
         &bin_dst,
     ) {
         Ok(()) => {}
-        Err(e) => eprintln!("Unable to copy the directory 'bin' from sysroot: {}", e),
+        Err(e) => messages.warning(&format!(
+            "unable to copy the directory 'bin' from sysroot: {}",
+            e
+        )),
     };
 
     util::write(&hfile, hash)?;
+    util::write(&lock.parent().join(TOOLCHAIN), &meta.short_version_string)?;
+    messages.emit(Message::ArtifactsCopied {
+        target: &meta.host,
+        path: lock.parent(),
+    });
 
     Ok(())
 }
+
+/// Removes the sysroot of `triple` and returns the number of bytes freed
+///
+/// If `meta` is passed, the sysroot is only removed if it was built by a different toolchain.
+/// Returns `None` if nothing was removed.
+pub fn remove(triple: &str, home: &Home, meta: Option<&VersionMeta>) -> Result<Option<u64>> {
+    if !home.triple_dir(triple).exists() {
+        return Ok(None);
+    }
+
+    let lock = home.lock_rw(triple)?;
+    let empty = lock
+        .parent()
+        .read_dir()
+        .with_context(|| format!("couldn't read {}", lock.parent().display()))?
+        .all(|entry| entry.map(|e| e.path() == lock.path()).unwrap_or(false));
+    if empty {
+        return Ok(None);
+    }
+
+    if let Some(meta) = meta {
+        let tfile = lock.parent().join(TOOLCHAIN);
+        if tfile.exists() && util::read(&tfile)? == meta.short_version_string {
+            return Ok(None);
+        }
+    }
+
+    let size = util::dir_size(lock.parent())?;
+    lock.remove_siblings()
+        .with_context(|| format!("couldn't clear {}", lock.path().display()))?;
+
+    Ok(Some(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patched_paths_of_numstat() {
+        assert!(patched_paths("").is_empty());
+        assert_eq!(
+            patched_paths("1\t2\tlibrary/core/src/lib.rs\0-\t-\tlibrary/a b.bin\0"),
+            ["library/core/src/lib.rs", "library/a b.bin"]
+        );
+        // renames list the old and the new path
+        assert_eq!(
+            patched_paths("0\t0\t\0library/alloc/old.rs\0src/new.rs\0"),
+            ["library/alloc/old.rs", "src/new.rs"]
+        );
+    }
+
+    #[test]
+    fn locked_git_commit_of_lockfile() {
+        let lockfile = r#"
+version = 3
+
+[[package]]
+name = "compiler_builtins"
+version = "0.1.150"
+source = "git+https://github.com/rust-lang/compiler-builtins?branch=master#0123abc"
+
+[[package]]
+name = "libc"
+version = "0.2.169"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "5aba"
+
+[[package]]
+name = "core"
+version = "0.0.0"
+"#;
+        assert_eq!(
+            locked_git_commit(lockfile, "compiler_builtins").as_deref(),
+            Some("0123abc")
+        );
+        assert_eq!(locked_git_commit(lockfile, "libc"), None);
+        assert_eq!(locked_git_commit(lockfile, "core"), None);
+        assert_eq!(locked_git_commit(lockfile, "missing"), None);
+        assert_eq!(locked_git_commit("not toml [", "compiler_builtins"), None);
+    }
+
+    #[test]
+    fn added_packages_of_lockfiles() {
+        let old = r#"
+version = 3
+
+[[package]]
+name = "core"
+version = "0.0.0"
+
+[[package]]
+name = "libc"
+version = "0.2.169"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+        assert!(added_packages(old, old).unwrap().is_empty());
+        assert!(added_packages(old, "version = 3").unwrap().is_empty());
+
+        let new = format!(
+            "{}{}",
+            old,
+            r#"
+[[package]]
+name = "libc"
+version = "0.2.170"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "sysroot"
+version = "0.0.0"
+"#
+        );
+        assert_eq!(
+            added_packages(old, &new).unwrap(),
+            [
+                ("libc".to_owned(), "0.2.170".to_owned()),
+                ("sysroot".to_owned(), "0.0.0".to_owned()),
+            ]
+        );
+    }
+
+    fn resolved(requested: &[&str]) -> Result<Vec<&'static str>> {
+        let requested = requested.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        Ok(resolve_crates(&requested)?.iter().map(|c| c.name).collect())
+    }
+
+    #[test]
+    fn resolve_crates_with_dependencies() {
+        assert_eq!(resolved(&["core"]).unwrap(), ["core"]);
+        assert_eq!(
+            resolved(&["alloc"]).unwrap(),
+            ["core", "compiler_builtins", "alloc"]
+        );
+        // The crates are in dependency order, whatever the order of the request
+        assert_eq!(
+            resolved(&["std", "alloc", "core"]).unwrap(),
+            ["core", "compiler_builtins", "alloc", "panic_abort", "std"]
+        );
+        assert_eq!(
+            resolved(&["test"]).unwrap(),
+            [
+                "core",
+                "compiler_builtins",
+                "alloc",
+                "panic_abort",
+                "panic_unwind",
+                "std",
+                "test"
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_unknown_crate() {
+        let err = resolved(&["alloc", "hashbrown"]).unwrap_err().to_string();
+        assert!(
+            err.starts_with("`hashbrown` can't be built as part of the sysroot"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("supported crates are: core, compiler_builtins"),
+            "{}",
+            err
+        );
+    }
+}
@@ -40,7 +40,7 @@ pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
                 .with_context(|| format!("Could not create directory `{}`", dst_file.display()))?;
         } else {
             // else copy the file
-            fs::copy(&src_file, &dst_file).with_context(|| {
+            fs::copy(src_file, &dst_file).with_context(|| {
                 format!(
                     "copying files from `{}` to `{}` failed",
                     src_file.display(),
@@ -53,15 +53,90 @@ pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Returns the size of the files in `path` and its subdirectories that removing `path` frees
+///
+/// On unix, a file with several hard links is counted once, and only if all of its links are
+/// in `path`. This way the sysroot files that are shared with the sysroot cache don't count.
+pub fn dir_size(path: &Path) -> Result<u64> {
+    size(path, false)
+}
+
+/// Returns the disk space that the files in `path` and its subdirectories take up
+///
+/// Unlike [`dir_size`], a file with several hard links is counted with its full size, even if
+/// some of its links are outside of `path`. On unix, it is still counted only once.
+pub fn disk_usage(path: &Path) -> Result<u64> {
+    size(path, true)
+}
+
+fn size(path: &Path, shared: bool) -> Result<u64> {
+    #[cfg(unix)]
+    let mut links = std::collections::HashMap::new();
+
+    let mut size = 0;
+    for e in WalkDir::new(path) {
+        let e = e.with_context(|| {
+            format!(
+                "intermittent IO error while iterating directory `{}`",
+                path.display()
+            )
+        })?;
+        let metadata = e
+            .metadata()
+            .with_context(|| format!("Could not retrieve metadata of `{}`", e.path().display()))?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            if metadata.nlink() > 1 {
+                let seen = links.entry((metadata.dev(), metadata.ino())).or_insert(0);
+                *seen += 1;
+                let counted = if shared { 1 } else { metadata.nlink() };
+                if *seen != counted {
+                    continue;
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = shared;
+
+        size += metadata.len();
+    }
+
+    Ok(size)
+}
+
+/// Formats a number of bytes like Cargo does, e.g. `1.5MiB`
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
 pub fn mkdir(path: &Path) -> Result<()> {
     fs::create_dir(path).with_context(|| format!("couldn't create directory {}", path.display()))
 }
 
 /// Parses `path` as TOML
 pub fn parse(path: &Path) -> Result<Value> {
-    Ok(read(path)?
+    read(path)?
         .parse::<Value>()
-        .map_err(|e| anyhow!("{} is not valid TOML: {}", path.display(), e))?)
+        .map_err(|e| anyhow!("{} is not valid TOML: {}", path.display(), e))
 }
 
 pub fn read(path: &Path) -> Result<String> {
@@ -98,3 +173,54 @@ pub fn write(path: &Path, contents: &str) -> Result<()> {
         .write_all(contents.as_bytes())
         .with_context(|| format!("couldn't write to {}", p))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_size_units() {
+        assert_eq!(human_size(0), "0B");
+        assert_eq!(human_size(1023), "1023B");
+        assert_eq!(human_size(1024), "1.0KiB");
+        assert_eq!(human_size(1536), "1.5KiB");
+        assert_eq!(human_size(10 << 20), "10.0MiB");
+        assert_eq!(human_size(3 << 30), "3.0GiB");
+        assert_eq!(human_size(5000 << 40), "5000.0TiB");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dir_size_counts_files_linked_from_outside_once_all_links_are_inside() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = td.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a"), [0; 10]).unwrap();
+        fs::write(dir.join("b"), [0; 100]).unwrap();
+        assert_eq!(dir_size(&dir).unwrap(), 110);
+
+        // a file that is also linked from outside isn't freed by removing `dir`
+        fs::hard_link(dir.join("b"), td.path().join("b")).unwrap();
+        assert_eq!(dir_size(&dir).unwrap(), 10);
+        assert_eq!(dir_size(td.path()).unwrap(), 110);
+
+        // links within `dir` are counted once
+        fs::hard_link(dir.join("a"), dir.join("c")).unwrap();
+        assert_eq!(dir_size(&dir).unwrap(), 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn disk_usage_counts_shared_files_once() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = td.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a"), [0; 10]).unwrap();
+        fs::write(dir.join("b"), [0; 100]).unwrap();
+        fs::hard_link(dir.join("b"), td.path().join("b")).unwrap();
+        fs::hard_link(dir.join("a"), dir.join("c")).unwrap();
+
+        assert_eq!(disk_usage(&dir).unwrap(), 110);
+        assert_eq!(disk_usage(td.path()).unwrap(), 110);
+    }
+}
@@ -8,37 +8,39 @@ use anyhow::{Context, Result};
 use rustc_version::VersionMeta;
 
 use crate::cargo::Rustflags;
-use crate::cli::Args;
 use crate::config::Config;
 use crate::extensions::CommandExt;
 use crate::flock::{FileLock, Filesystem};
+use crate::message::Messages;
 use crate::CompilationMode;
 
+/// Runs the cargo command for all targets in `cmodes`, which share the same `rustflags`
 pub fn run(
-    args: &Args,
-    cmode: &CompilationMode,
-    rustflags: Rustflags,
+    args: &[String],
+    cmodes: &[&CompilationMode],
+    rustflags: &Rustflags,
     home: &Home,
     meta: &VersionMeta,
     command_name: &str,
-    verbose: bool,
+    messages: &Messages,
 ) -> Result<ExitStatus> {
     let cargo = std::env::var("CARGO").unwrap_or("cargo".to_string());
     let mut cmd = Command::new(cargo);
     cmd.arg(command_name);
-    cmd.args(args.all());
+    cmd.args(args);
 
-    let flags = rustflags.for_xargo(home)?;
-    if verbose {
-        eprintln!("+ RUSTFLAGS={:?}", flags);
-        eprintln!("+ RUSTDOCFLAGS={:?}", flags);
-    }
-    cmd.env("RUSTFLAGS", &flags);
-    cmd.env("RUSTDOCFLAGS", &flags);
+    // The flags are shown as part of the command by `--verbose`
+    let flags = rustflags.for_xargo(home);
+    // Cargo prefers the encoded variables, so the plain ones would only be confusing
+    cmd.env_remove("RUSTFLAGS");
+    cmd.env_remove("RUSTDOCFLAGS");
+    cmd.env("CARGO_ENCODED_RUSTFLAGS", &flags);
+    cmd.env("CARGO_ENCODED_RUSTDOCFLAGS", &flags);
 
-    let locks = (home.lock_ro(&meta.host), home.lock_ro(cmode.triple()));
+    let mut locks = vec![home.lock_ro(&meta.host)];
+    locks.extend(cmodes.iter().map(|cmode| home.lock_ro(cmode.triple())));
 
-    let status = cmd.run_and_get_status(verbose)?;
+    let status = cmd.run_and_get_status(messages)?;
 
     mem::drop(locks);
 
@@ -50,23 +52,54 @@ pub struct Home {
 }
 
 impl Home {
-    pub fn display(&self) -> Display {
+    pub fn display(&self) -> Display<'_> {
         self.path.display()
     }
 
-    fn path(&self, triple: &str) -> Filesystem {
+    pub fn path(&self) -> &Path {
+        self.path.path()
+    }
+
+    /// The directory that holds the sysroot of `triple`
+    pub fn triple_dir(&self, triple: &str) -> PathBuf {
+        self.triple_path(triple).path().to_owned()
+    }
+
+    /// The triples for which a sysroot exists
+    pub fn triples(&self) -> Result<Vec<String>> {
+        let rustlib = self.path().join("lib").join("rustlib");
+        if !rustlib.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut triples = vec![];
+        for entry in rustlib
+            .read_dir()
+            .with_context(|| format!("couldn't read {}", rustlib.display()))?
+        {
+            let entry = entry.with_context(|| format!("couldn't read {}", rustlib.display()))?;
+            if entry.path().is_dir() {
+                triples.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        triples.sort();
+
+        Ok(triples)
+    }
+
+    fn triple_path(&self, triple: &str) -> Filesystem {
         self.path.join("lib").join("rustlib").join(triple)
     }
 
     pub fn lock_ro(&self, triple: &str) -> Result<FileLock> {
-        let fs = self.path(triple);
+        let fs = self.triple_path(triple);
 
         fs.open_ro(".sentinel", &format!("{}'s sysroot", triple))
             .with_context(|| format!("couldn't lock {}'s sysroot as read-only", triple))
     }
 
     pub fn lock_rw(&self, triple: &str) -> Result<FileLock> {
-        let fs = self.path(triple);
+        let fs = self.triple_path(triple);
 
         fs.open_rw(".sentinel", &format!("{}'s sysroot", triple))
             .with_context(|| {
@@ -79,16 +112,77 @@ impl Home {
     }
 }
 
-pub fn home(root: &Path, config: &Config, quiet: bool) -> Result<Home> {
-    let path = if let Ok(path) = env::var("XBUILD_SYSROOT_PATH") {
-        PathBuf::from(path)
-    } else {
-        let mut path = PathBuf::from(root);
-        path.push(&config.sysroot_path);
-        path
+/// The directory that holds the sysroots of all profiles
+fn base(root: &Path, config: &Config) -> PathBuf {
+    let mut path = PathBuf::from(root);
+    path.push(&config.sysroot_path);
+    path
+}
+
+/// The sysroot for building with `profile`
+///
+/// The `XBUILD_SYSROOT_PATH` environment variable sets the sysroot for all profiles, which is
+/// rebuilt when the profile changes.
+pub fn home(root: &Path, config: &Config, profile: &str, messages: &Messages) -> Result<Home> {
+    let path = match env::var("XBUILD_SYSROOT_PATH") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => base(root, config).join(profile),
     };
 
     Ok(Home {
-        path: Filesystem::new(path, quiet),
+        path: Filesystem::new(path, messages),
     })
 }
+
+/// The sysroots of all profiles that have been built, by profile name
+///
+/// A sysroot in the layout of older versions, which placed it directly in the base
+/// directory for all profiles, is included with the name `legacy`, so that it can be removed.
+/// If `XBUILD_SYSROOT_PATH` is set, the sysroot there is the only one, with the name of the
+/// variable.
+pub fn homes(root: &Path, config: &Config, messages: &Messages) -> Result<Vec<(String, Home)>> {
+    if let Ok(path) = env::var("XBUILD_SYSROOT_PATH") {
+        let path = PathBuf::from(path);
+        if !path.join("lib").join("rustlib").is_dir() {
+            return Ok(vec![]);
+        }
+        return Ok(vec![(
+            "XBUILD_SYSROOT_PATH".to_owned(),
+            Home {
+                path: Filesystem::new(path, messages),
+            },
+        )]);
+    }
+
+    let base = base(root, config);
+    if !base.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut homes = vec![];
+    if base.join("lib").join("rustlib").is_dir() {
+        homes.push((
+            "legacy".to_owned(),
+            Home {
+                path: Filesystem::new(base.clone(), messages),
+            },
+        ));
+    }
+    for entry in base
+        .read_dir()
+        .with_context(|| format!("couldn't read {}", base.display()))?
+    {
+        let entry = entry.with_context(|| format!("couldn't read {}", base.display()))?;
+        if entry.path().join("lib").join("rustlib").is_dir() {
+            homes.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                Home {
+                    path: Filesystem::new(entry.path(), messages),
+                },
+            ));
+        }
+    }
+    homes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(homes)
+}